@@ -0,0 +1,85 @@
+use std::{
+  collections::hash_map::DefaultHasher,
+  hash::{Hash, Hasher},
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+  },
+};
+
+use parking_lot::Mutex;
+
+use crate::{error::Result, magic_string::MagicString};
+
+use super::{AddSourceOptions, Bundle, BundleOptions};
+
+struct PendingSource<K> {
+  key: K,
+  /// order of the [ConcurrentBundleBuilder::add_source] call, breaks the ties of equal keys
+  index: usize,
+  source: Arc<MagicString>,
+  opts: Option<AddSourceOptions>,
+}
+
+/// Collects sources from many threads, each with a sort key, then builds a [Bundle] with the sources ordered by key.
+///
+/// Sources are stored in shards picked by the adding thread, so threads rarely wait on each other.
+/// Sources with equal keys are added in the order [ConcurrentBundleBuilder::add_source] was called.
+pub struct ConcurrentBundleBuilder<K> {
+  options: BundleOptions,
+  shards: Vec<Mutex<Vec<PendingSource<K>>>>,
+  added_count: AtomicUsize,
+}
+
+impl<K: Ord + Send> ConcurrentBundleBuilder<K> {
+  pub fn new(options: BundleOptions) -> Self {
+    let shard_count = std::thread::available_parallelism()
+      .map(|count| count.get())
+      .unwrap_or(1)
+      * 4;
+
+    Self {
+      options,
+      shards: (0..shard_count).map(|_| Mutex::new(vec![])).collect(),
+      added_count: AtomicUsize::new(0),
+    }
+  }
+
+  /// Add a source that is placed by `key` when the bundle is built. It can be called from multiple threads.
+  pub fn add_source(
+    &self,
+    key: K,
+    source: impl Into<Arc<MagicString>>,
+    opts: Option<AddSourceOptions>,
+  ) {
+    let mut hasher = DefaultHasher::new();
+    std::thread::current().id().hash(&mut hasher);
+    let shard = &self.shards[hasher.finish() as usize % self.shards.len()];
+
+    shard.lock().push(PendingSource {
+      key,
+      index: self.added_count.fetch_add(1, Ordering::Relaxed),
+      source: source.into(),
+      opts,
+    });
+  }
+
+  /// Build the bundle, sources are added in the order of their keys.
+  /// Fails like [Bundle::add_source] does, e.g. for a duplicate filename with different content.
+  pub fn build(self) -> Result<Bundle> {
+    let mut pending_sources = self
+      .shards
+      .into_iter()
+      .flat_map(|shard| shard.into_inner())
+      .collect::<Vec<_>>();
+    pending_sources.sort_by(|a, b| a.key.cmp(&b.key).then(a.index.cmp(&b.index)));
+
+    let mut bundle = Bundle::new(self.options);
+
+    for pending_source in pending_sources {
+      bundle.add_source(pending_source.source, pending_source.opts)?;
+    }
+
+    Ok(bundle)
+  }
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+
+use sourcemap::SourceMap;
+
+use crate::magic_string::MagicString;
+
+use super::output::{PieceSink, TextKind};
+
+/// A position in the generated code. `line` and `column` are 0-based and counted in chars like the source map,
+/// `offset` is the byte offset from the start of the generated code.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GeneratedPosition {
+  pub line: usize,
+  pub column: usize,
+  pub offset: usize,
+}
+
+/// Where a source of a bundle is placed in the generated code and how many bytes it contributes.
+/// All sizes are in bytes of the generated code.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SourceLayout {
+  /// index of the source in the bundle, in the order of [super::Bundle::add_source]
+  pub source_index: usize,
+  /// filename of the source in the generated source map, `None` for an anonymous source
+  pub filename: Option<String>,
+  /// start of the source, after the separator before it
+  pub start: GeneratedPosition,
+  pub end: GeneratedPosition,
+  /// unedited content of the source
+  pub content_size: usize,
+  /// separator before the source
+  pub separator_size: usize,
+  /// intro and outro of the source, including the ones set by [super::AddSourceOptions]
+  pub wrapper_size: usize,
+  /// intro and outro of chunks, and edited chunks
+  pub insertion_size: usize,
+  /// indent inserted by [super::Bundle::indent]
+  pub indent_size: usize,
+  /// bytes of the source mapped back to each original file through the source map chain, in order of first appearance.
+  /// Bytes that are not mapped are not included.
+  pub original_sizes: Vec<(String, usize)>,
+}
+
+/// Layout of a generated bundle, e.g. for a bundle analyzer or a runtime module offset table.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BundleManifest {
+  /// size of the whole generated code in bytes
+  pub size: usize,
+  /// intro of the bundle and [super::Bundle::wrap], indent of the intro included
+  pub bundle_size: usize,
+  /// the sources, in output order
+  pub sources: Vec<SourceLayout>,
+}
+
+/// Records the layout of the sources from the pieces of a bundle output.
+#[derive(Default)]
+pub(crate) struct LayoutSink {
+  pub manifest: BundleManifest,
+  /// generated code, used to attribute the mapped bytes afterwards
+  pub code: String,
+  position: GeneratedPosition,
+  pending_separator_size: usize,
+  current: Option<SourceLayout>,
+}
+
+impl LayoutSink {
+  fn advance(&mut self, chars: &[char]) -> usize {
+    let start_offset = self.position.offset;
+
+    for char in chars {
+      self.code.push(*char);
+      self.position.offset += char.len_utf8();
+
+      if *char == '\n' {
+        self.position.line += 1;
+        self.position.column = 0;
+      } else {
+        self.position.column += 1;
+      }
+    }
+
+    self.position.offset - start_offset
+  }
+
+  /// Attribute the bytes of every token span of `map` to the original source of the token,
+  /// a span ends at the next token of the same line or at the end of the line.
+  /// `map` must be generated from `self.code`.
+  pub fn attribute_original_sizes(&mut self, map: &SourceMap) {
+    let mut sizes_by_layout: Vec<HashMap<&str, usize>> =
+      vec![HashMap::new(); self.manifest.sources.len()];
+    let mut order_by_layout: Vec<Vec<&str>> = vec![vec![]; self.manifest.sources.len()];
+    let lines = self.code.split('\n').collect::<Vec<_>>();
+    let mut line_offset = 0;
+    let mut tokens = map.tokens().peekable();
+
+    for (line_index, line) in lines.iter().enumerate() {
+      // byte offset of each char of the line, and the end of the line
+      let char_offsets = line
+        .char_indices()
+        .map(|(offset, _)| offset)
+        .chain(std::iter::once(line.len()))
+        .collect::<Vec<_>>();
+
+      while let Some(token) = tokens.next_if(|token| token.get_dst_line() as usize == line_index) {
+        let start_col = (token.get_dst_col() as usize).min(char_offsets.len() - 1);
+        let end_col = tokens
+          .peek()
+          .filter(|next| next.get_dst_line() == token.get_dst_line())
+          .map(|next| next.get_dst_col() as usize)
+          .unwrap_or(char_offsets.len() - 1)
+          .min(char_offsets.len() - 1);
+
+        let (Some(source), true) = (token.get_source(), end_col > start_col) else {
+          continue;
+        };
+
+        let start_offset = line_offset + char_offsets[start_col];
+        let layout_index = self
+          .manifest
+          .sources
+          .partition_point(|layout| layout.end.offset <= start_offset);
+
+        if layout_index < self.manifest.sources.len()
+          && self.manifest.sources[layout_index].start.offset <= start_offset
+        {
+          // the span must not reach into the next source, e.g. its separator
+          let end_offset = (line_offset + char_offsets[end_col])
+            .min(self.manifest.sources[layout_index].end.offset);
+          let size = end_offset - start_offset;
+          let sizes = &mut sizes_by_layout[layout_index];
+
+          if !sizes.contains_key(source) {
+            order_by_layout[layout_index].push(source);
+          }
+
+          *sizes.entry(source).or_default() += size;
+        }
+      }
+
+      line_offset += line.len() + 1;
+    }
+
+    for (layout_index, layout) in self.manifest.sources.iter_mut().enumerate() {
+      layout.original_sizes = order_by_layout[layout_index]
+        .iter()
+        .map(|source| (source.to_string(), sizes_by_layout[layout_index][source]))
+        .collect();
+    }
+  }
+}
+
+impl PieceSink for LayoutSink {
+  fn text(&mut self, kind: TextKind, text: &[char]) {
+    let size = self.advance(text);
+
+    match (kind, self.current.as_mut()) {
+      (TextKind::Separator, _) => self.pending_separator_size += size,
+      (TextKind::Bundle, _) | (TextKind::Indent, None) => self.manifest.bundle_size += size,
+      (TextKind::Wrapper, Some(layout)) => layout.wrapper_size += size,
+      (TextKind::Insertion, Some(layout)) => layout.insertion_size += size,
+      (TextKind::Indent, Some(layout)) => layout.indent_size += size,
+      (TextKind::Wrapper | TextKind::Insertion, None) => {
+        unreachable!("source text must be emitted between begin_source and end_source")
+      }
+    }
+  }
+
+  fn source_text(&mut self, _: usize, source: &MagicString, start: usize, end: usize) {
+    let size = self.advance(&source.original.as_slice()[start..end]);
+
+    if let Some(layout) = self.current.as_mut() {
+      layout.content_size += size;
+    }
+  }
+
+  fn begin_source(&mut self, source_index: usize) {
+    self.current = Some(SourceLayout {
+      source_index,
+      start: self.position,
+      separator_size: std::mem::take(&mut self.pending_separator_size),
+      ..Default::default()
+    });
+  }
+
+  fn end_source(&mut self, _: usize) {
+    if let Some(mut layout) = self.current.take() {
+      layout.end = self.position;
+      self.manifest.sources.push(layout);
+    }
+  }
+}
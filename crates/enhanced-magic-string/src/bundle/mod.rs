@@ -0,0 +1,1115 @@
+use std::{collections::HashMap, fmt::Display, io::Write, sync::Arc};
+
+use sourcemap::SourceMapBuilder;
+
+use crate::{
+  bundle::{
+    manifest::LayoutSink,
+    output::{FmtSink, IndentTrimFilter, PieceSink, TeeSink, TextKind, TrimEndFinder, WriteSink},
+  },
+  collapse_sourcemap::{
+    read_source_content, resolve_token_source, resolve_traced_line, trace_tokens,
+    ExtensionsCollector, TracedMapping,
+  },
+  error::{Error, Result},
+  magic_string::MagicString,
+  mappings::Mappings,
+  source_map::{DecodedSourceMap, GeneratedCode, GeneratedSourceMap},
+  source_resolver::resolver_or_fs,
+  types::{MappingsOptionHires, SourceMapOptions},
+  utils::{
+    char_string::CharString,
+    get_locator::{get_locator, Loc, Locator},
+    source_path,
+  },
+};
+
+mod concurrent;
+mod manifest;
+mod output;
+
+pub use concurrent::ConcurrentBundleBuilder;
+pub use manifest::{BundleManifest, GeneratedPosition, SourceLayout};
+
+#[derive(Default)]
+pub struct BundleOptions {
+  /// Separator between sources, defaults to `"\n"`. An empty string means no separator.
+  pub separator: Option<CharString>,
+  pub intro: Option<CharString>,
+  pub trace_source_map_chain: Option<bool>,
+  pub duplicate_filename_policy: Option<DuplicateFilenamePolicy>,
+  /// If true, sources added without a filename get a synthesized virtual filename like `farm:virtual/0`,
+  /// so that generated code like runtime helpers shows up in the source map. [Bundle::append] is not affected.
+  /// The content of virtual sources is inlined unless [AddSourceOptions::include_content] or
+  /// [SourceMapOptions::include_content] is `false`.
+  pub virtual_sources: Option<bool>,
+}
+
+/// What [Bundle::add_source] does when a filename is added again with different content.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateFilenamePolicy {
+  /// Return [Error::IllegalSource].
+  #[default]
+  Error,
+  /// Add the source under a suffixed filename, e.g. `a.js?v=2`, `a.js?v=3`.
+  Disambiguate,
+  /// All sources of that filename share one entry of `sources`, its `sourcesContent` is the content of the last added
+  /// source. The mappings and the source map chain of each source are still its own.
+  LastWins,
+}
+
+struct UniqueSource {
+  pub filename: String,
+  /// the filename the source was added with, `filename` may be suffixed by [DuplicateFilenamePolicy::Disambiguate]
+  pub added_filename: String,
+  pub content: CharString,
+  /// index of the source in the bundle that the content comes from
+  pub source_index: usize,
+  /// virtual sources do not exist on disk, their content is inlined to the source map unless `include_content` is
+  /// `false`
+  pub is_virtual: bool,
+}
+
+#[derive(Default)]
+pub struct AddSourceOptions {
+  /// Separator between this source and the previous one, `None` means [BundleOptions::separator] and an empty string
+  /// means no separator. [Bundle::append] defaults to no separator.
+  pub separator: Option<CharString>,
+  pub filename: Option<String>,
+  /// Content inserted before the source in this bundle only, the shared source itself is not modified.
+  pub intro: Option<CharString>,
+  /// Content inserted after the source in this bundle only, the shared source itself is not modified.
+  pub outro: Option<CharString>,
+  /// Virtual filename for a source without a filename, it's used instead of the one synthesized by
+  /// [BundleOptions::virtual_sources]. Ignored if the source has a filename.
+  pub virtual_filename: Option<String>,
+  /// Overrides [SourceMapOptions::hires] for this source, e.g. lo-res mappings for a huge vendor module.
+  pub hires: Option<MappingsOptionHires>,
+  /// Overrides [SourceMapOptions::include_content] for this source.
+  pub include_content: Option<bool>,
+  /// Overrides [BundleOptions::trace_source_map_chain] for this source.
+  pub trace_source_map_chain: Option<bool>,
+  /// If true, the source is added to the `x_google_ignoreList` of the generated source map.
+  pub ignore: Option<bool>,
+}
+
+/// A source added to a bundle. The [MagicString] may be shared by multiple bundles,
+/// so everything specific to this bundle is held here instead of in the source.
+struct BundleSource {
+  source: Arc<MagicString>,
+  unique_source_index: Option<usize>,
+  separator: Option<CharString>,
+  intro: CharString,
+  outro: CharString,
+  hires: Option<MappingsOptionHires>,
+  include_content: Option<bool>,
+  trace_source_map_chain: Option<bool>,
+  ignore: bool,
+}
+
+/// A unique source that is emitted to a generated source map, with the options of the first bundle source emitting it.
+struct EmittedSource {
+  unique_source_index: usize,
+  include_content: bool,
+  trace_source_map_chain: bool,
+  ignore: bool,
+  ignore_list: Vec<CharString>,
+}
+
+impl EmittedSource {
+  /// Whether `source` of the generated map is added to `x_google_ignoreList`, by [AddSourceOptions::ignore] or by
+  /// [crate::magic_string::MagicStringOptions::ignore_list].
+  fn is_ignored(&self, source: &str) -> bool {
+    self.ignore
+      || self
+        .ignore_list
+        .iter()
+        .any(|entry| source_path::is_same_file(&entry.to_string(), source))
+  }
+}
+
+/// How [Bundle::split] partitions the sources of a bundle into outputs.
+/// Sources are referred to by the order they were added with [Bundle::add_source].
+pub enum SplitStrategy {
+  /// Each group of source indexes becomes one output, sources are emitted in the given order.
+  /// Groups must not be empty, and a source can only be in one group.
+  Groups(Vec<Vec<usize>>),
+  /// Consecutive sources are packed into outputs of at most the given size in bytes, separators and the intro and
+  /// wrapper of the bundle included. A source that is larger than the budget gets an output of its own.
+  MaxSize(usize),
+}
+
+pub struct Bundle {
+  separator: Option<CharString>,
+  intro: CharString,
+  sources: Vec<BundleSource>,
+  unique_sources: Vec<UniqueSource>,
+  unique_source_index_by_filename: HashMap<String, usize>,
+  trace_source_map_chain: bool,
+  duplicate_filename_policy: DuplicateFilenamePolicy,
+  virtual_sources: bool,
+  virtual_source_count: usize,
+  indent_str: Option<CharString>,
+  wrap_before: CharString,
+  wrap_after: CharString,
+  trim_start: bool,
+  trim_end: bool,
+}
+
+impl Bundle {
+  pub fn new(options: BundleOptions) -> Self {
+    Self {
+      separator: options.separator.unwrap_or_else(|| "\n".into()).non_empty(),
+      intro: options.intro.unwrap_or("".into()),
+      sources: vec![],
+      unique_sources: vec![],
+      unique_source_index_by_filename: HashMap::new(),
+      trace_source_map_chain: options.trace_source_map_chain.unwrap_or(false),
+      duplicate_filename_policy: options.duplicate_filename_policy.unwrap_or_default(),
+      virtual_sources: options.virtual_sources.unwrap_or(false),
+      virtual_source_count: 0,
+      indent_str: None,
+      wrap_before: CharString::default(),
+      wrap_after: CharString::default(),
+      trim_start: false,
+      trim_end: false,
+    }
+  }
+
+  /// Add a source to the bundle. The source can be shared with other bundles by passing an `Arc<MagicString>`,
+  /// options in `opts` only apply to this bundle.
+  pub fn add_source(
+    &mut self,
+    source: impl Into<Arc<MagicString>>,
+    opts: Option<AddSourceOptions>,
+  ) -> Result<()> {
+    self.add_bundle_source(source.into(), opts, self.virtual_sources)
+  }
+
+  /// Add a source, it gets a synthesized virtual filename if it has none and `synthesize_virtual_filename` is true.
+  fn add_bundle_source(
+    &mut self,
+    source: Arc<MagicString>,
+    opts: Option<AddSourceOptions>,
+    synthesize_virtual_filename: bool,
+  ) -> Result<()> {
+    let opts = opts.unwrap_or_default();
+    let filename = opts.filename.or_else(|| source.filename.clone());
+    let unique_source_index = if let Some(filename) = filename {
+      Some(self.add_unique_source(filename, &source.original, false)?)
+    } else if let Some(virtual_filename) = opts.virtual_filename {
+      Some(self.add_unique_source(virtual_filename, &source.original, true)?)
+    } else if synthesize_virtual_filename {
+      let virtual_filename = format!("farm:virtual/{}", self.virtual_source_count);
+      self.virtual_source_count += 1;
+
+      Some(self.add_unique_source(virtual_filename, &source.original, true)?)
+    } else {
+      None
+    };
+
+    self.sources.push(BundleSource {
+      source,
+      unique_source_index,
+      separator: match opts.separator {
+        Some(separator) => separator.non_empty(),
+        None => self.separator.clone(),
+      },
+      intro: opts.intro.unwrap_or_default(),
+      outro: opts.outro.unwrap_or_default(),
+      hires: opts.hires,
+      include_content: opts.include_content,
+      trace_source_map_chain: opts.trace_source_map_chain,
+      ignore: opts.ignore.unwrap_or(false),
+    });
+
+    Ok(())
+  }
+
+  /// Register the content of the source that is about to be added under `filename`, following [DuplicateFilenamePolicy].
+  /// Returns the index of the unique source the source maps to.
+  fn add_unique_source(
+    &mut self,
+    filename: String,
+    content: &CharString,
+    is_virtual: bool,
+  ) -> Result<usize> {
+    let Some(&index) = self.unique_source_index_by_filename.get(&filename) else {
+      return Ok(self.push_unique_source(filename.clone(), filename, content, is_virtual));
+    };
+
+    if self.unique_sources[index].content == *content {
+      return Ok(index);
+    }
+
+    match self.duplicate_filename_policy {
+      DuplicateFilenamePolicy::Error => Err(Error::IllegalSource),
+      DuplicateFilenamePolicy::LastWins => {
+        // each content keeps a unique source of its own, so its mappings and chain stay with it, the filename points
+        // at the last added one, whose content is written to `sourcesContent`
+        let index = self
+          .unique_sources
+          .iter()
+          .position(|unique_source| {
+            unique_source.filename == filename && unique_source.content == *content
+          })
+          .unwrap_or_else(|| {
+            self.push_unique_source(filename.clone(), filename.clone(), content, is_virtual)
+          });
+        self.unique_source_index_by_filename.insert(filename, index);
+
+        Ok(index)
+      }
+      DuplicateFilenamePolicy::Disambiguate => {
+        for version in 2.. {
+          let versioned_filename = format!("{filename}?v={version}");
+
+          match self
+            .unique_source_index_by_filename
+            .get(&versioned_filename)
+          {
+            None => {
+              return Ok(self.push_unique_source(versioned_filename, filename, content, is_virtual))
+            }
+            // the versioned filename may also be the real filename of another source
+            Some(&index)
+              if self.unique_sources[index].added_filename == filename
+                && self.unique_sources[index].content == *content =>
+            {
+              return Ok(index)
+            }
+            Some(_) => {}
+          }
+        }
+
+        unreachable!()
+      }
+    }
+  }
+
+  fn push_unique_source(
+    &mut self,
+    filename: String,
+    added_filename: String,
+    content: &CharString,
+    is_virtual: bool,
+  ) -> usize {
+    let index = self.unique_sources.len();
+    self
+      .unique_source_index_by_filename
+      .insert(filename.clone(), index);
+    self.unique_sources.push(UniqueSource {
+      filename,
+      added_filename,
+      content: content.clone(),
+      source_index: self.sources.len(),
+      is_virtual,
+    });
+
+    index
+  }
+
+  /// [SourceMapOptions::url_comment] is ignored, only the map is generated.
+  pub fn generate_map(&self, opts: SourceMapOptions) -> Result<GeneratedSourceMap> {
+    let source_indexes = (0..self.sources.len()).collect::<Vec<_>>();
+
+    self.generate_map_of_sources(&source_indexes, opts)
+  }
+
+  /// Like `generateDecodedMap` of magic-string, the mappings are not encoded.
+  pub fn generate_decoded_map(&self, opts: SourceMapOptions) -> Result<DecodedSourceMap> {
+    Ok(self.generate_map(opts)?.to_decoded())
+  }
+
+  /// Generate the code and the source map together, the code and the mappings are produced by the same traversal of
+  /// the sources, so they are always consistent. If [Bundle::trim] is set, the sources are traversed once more
+  /// before to find where the trailing whitespace starts. Tracing the source map chains iterates the mappings again.
+  /// A `sourceMappingURL` comment is appended to the code if [SourceMapOptions::url_comment] is set.
+  pub fn generate(&self, opts: SourceMapOptions) -> Result<GeneratedCode> {
+    let source_indexes = (0..self.sources.len()).collect::<Vec<_>>();
+
+    generate_code(opts, |code_sink, opts| {
+      self.generate_of_sources(&source_indexes, opts, code_sink)
+    })
+  }
+
+  /// Same as [Bundle::generate], but the code is written to `writer` while the source map is generated.
+  /// The code is written piece by piece, wrap `writer` in a [std::io::BufWriter] if writes are expensive.
+  pub fn generate_to_writer<W: Write>(
+    &self,
+    writer: W,
+    opts: SourceMapOptions,
+  ) -> Result<GeneratedSourceMap> {
+    let source_indexes = (0..self.sources.len()).collect::<Vec<_>>();
+
+    generate_to_writer(writer, opts, |code_sink, opts| {
+      self.generate_of_sources(&source_indexes, opts, code_sink)
+    })
+  }
+
+  /// Layout of the generated code and the size contribution of each source, see [BundleManifest].
+  /// `opts` is used to generate the source map that attributes the bytes of the sources to the original files.
+  pub fn manifest(&self, opts: SourceMapOptions) -> Result<BundleManifest> {
+    let source_indexes = (0..self.sources.len()).collect::<Vec<_>>();
+
+    self.manifest_of_sources(&source_indexes, opts)
+  }
+
+  /// Split the sources of this bundle into multiple outputs, see [SplitStrategy].
+  /// Every output starts with the intro of this bundle, and its source map only contains the sources it emits.
+  pub fn split(&self, strategy: SplitStrategy) -> Result<Vec<BundleOutput<'_>>> {
+    let groups = match strategy {
+      SplitStrategy::Groups(groups) => {
+        let mut is_grouped = vec![false; self.sources.len()];
+
+        for (group_index, group) in groups.iter().enumerate() {
+          if group.is_empty() {
+            return Err(Error::EmptySplitGroup(group_index));
+          }
+
+          for index in group {
+            match is_grouped.get_mut(*index) {
+              None => return Err(Error::SourceIndexOutOfBounds(*index)),
+              Some(true) => return Err(Error::DuplicateSourceIndex(*index)),
+              Some(is_grouped) => *is_grouped = true,
+            }
+          }
+        }
+
+        groups
+      }
+      SplitStrategy::MaxSize(max_size) => {
+        // every output starts with the intro and is wrapped
+        let output_overhead =
+          self.intro.byte_len() + self.wrap_before.byte_len() + self.wrap_after.byte_len();
+        let mut groups: Vec<Vec<usize>> = vec![];
+        let mut group_size = 0;
+
+        for (index, source) in self.sources.iter().enumerate() {
+          let source_size =
+            source.intro.byte_len() + source_byte_len(&source.source) + source.outro.byte_len();
+
+          match groups.last_mut() {
+            Some(group)
+              if group_size + separator_byte_len(&source.separator) + source_size <= max_size =>
+            {
+              group_size += separator_byte_len(&source.separator) + source_size;
+              group.push(index);
+            }
+            _ => {
+              group_size = output_overhead + source_size;
+              groups.push(vec![index]);
+            }
+          }
+        }
+
+        groups
+      }
+    };
+
+    Ok(
+      groups
+        .into_iter()
+        .map(|source_indexes| BundleOutput {
+          bundle: self,
+          source_indexes,
+        })
+        .collect(),
+    )
+  }
+
+  fn manifest_of_sources(
+    &self,
+    source_indexes: &[usize],
+    opts: SourceMapOptions,
+  ) -> Result<BundleManifest> {
+    let mut layout_sink = LayoutSink::default();
+    self.write_output(source_indexes, &mut layout_sink);
+    layout_sink.manifest.size = layout_sink.code.len();
+
+    for layout in layout_sink.manifest.sources.iter_mut() {
+      layout.filename = self.sources[layout.source_index]
+        .unique_source_index
+        .map(|unique_index| self.unique_sources[unique_index].filename.clone());
+    }
+
+    let map = self.generate_map_of_sources(source_indexes, opts)?;
+    layout_sink.attribute_original_sizes(&map);
+
+    Ok(layout_sink.manifest)
+  }
+
+  fn generate_map_of_sources(
+    &self,
+    source_indexes: &[usize],
+    opts: SourceMapOptions,
+  ) -> Result<GeneratedSourceMap> {
+    self.generate_of_sources(source_indexes, opts, &mut ())
+  }
+
+  /// Generate the source map of the sources, the generated code is passed to `code_sink` in the same traversal.
+  fn generate_of_sources(
+    &self,
+    source_indexes: &[usize],
+    opts: SourceMapOptions,
+    code_sink: &mut impl PieceSink,
+  ) -> Result<GeneratedSourceMap> {
+    let mut names = vec![];
+    // the sources of the generated map are the unique sources that are emitted, in the order they first appear
+    let mut emitted_sources = vec![];
+    let mut source_index_by_unique_index = HashMap::new();
+
+    source_indexes.iter().for_each(|i| {
+      let bundle_source = &self.sources[*i];
+
+      bundle_source
+        .source
+        .stored_names
+        .iter()
+        .for_each(|(name, _)| {
+          names.push(name.clone());
+        });
+
+      if let Some(unique_index) = bundle_source.unique_source_index {
+        source_index_by_unique_index
+          .entry(unique_index)
+          .or_insert_with(|| {
+            emitted_sources.push(EmittedSource {
+              unique_source_index: unique_index,
+              include_content: bundle_source
+                .include_content
+                .or(opts.include_content)
+                .unwrap_or(self.unique_sources[unique_index].is_virtual),
+              trace_source_map_chain: bundle_source
+                .trace_source_map_chain
+                .unwrap_or(self.trace_source_map_chain),
+              ignore: bundle_source.ignore,
+              ignore_list: bundle_source.source.ignore_list.clone(),
+            });
+            emitted_sources.len() - 1
+          });
+      }
+    });
+
+    let mut mappings_sink = MappingsSink {
+      mappings: Mappings::new(),
+      hires: opts.hires.unwrap_or_default(),
+      sources: &self.sources,
+      unique_sources: &self.unique_sources,
+      source_index_by_unique_index: &source_index_by_unique_index,
+      locator: None,
+    };
+    self.write_output(
+      source_indexes,
+      &mut TeeSink {
+        a: &mut mappings_sink,
+        b: code_sink,
+      },
+    );
+    let mut mappings = mappings_sink.mappings;
+
+    let mut sources: Vec<Arc<str>> = vec![];
+    // the content of every source, the intermediate map of the tracing needs it even if it is not emitted
+    let mut sources_content: Vec<Option<Arc<str>>> = vec![];
+
+    emitted_sources.iter().for_each(|emitted_source| {
+      let source = &self.unique_sources[emitted_source.unique_source_index];
+      let filename = if let Some(file) = &opts.file {
+        source_path::relative(file, &source.filename)
+      } else {
+        source_path::normalize(&source.filename)
+      };
+      let filename = if let Some(remap_source) = &opts.remap_source {
+        remap_source(&filename)
+      } else {
+        filename
+      };
+      // sources of the same filename share the content of the last added one, see [DuplicateFilenamePolicy::LastWins]
+      let content =
+        &self.unique_sources[self.unique_source_index_by_filename[&source.filename]].content;
+      sources.push(filename.into());
+      sources_content.push(Some(content.to_string().into()));
+    });
+
+    let mut unique_names: Vec<Arc<str>> = vec![];
+    let mut name_index_by_name: HashMap<Arc<str>, u32> = HashMap::new();
+    names.into_iter().for_each(|name| {
+      let name: Arc<str> = name.to_string().into();
+
+      name_index_by_name.entry(name).or_insert_with_key(|name| {
+        unique_names.push(name.clone());
+        unique_names.len() as u32 - 1
+      });
+    });
+
+    if emitted_sources
+      .iter()
+      .any(|emitted_source| emitted_source.trace_source_map_chain)
+    {
+      let line_count = mappings.line_count();
+      let map =
+        mappings.into_sourcemap(opts.file.as_deref(), sources, sources_content, unique_names);
+      // try trace back to original sourcemap of each source
+      let mut trace_sourcemap_builder = SourceMapBuilder::new(opts.file.as_deref());
+      let mut mapped_src_cache = HashMap::new();
+      let mut ignored_src_ids = vec![];
+
+      // the source map chain of each emitted source, empty if it is not traced
+      let emitted_source_map_chains = emitted_sources
+        .iter()
+        .map(|emitted_source| {
+          if !emitted_source.trace_source_map_chain {
+            return Arc::default();
+          }
+
+          let unique_source = &self.unique_sources[emitted_source.unique_source_index];
+          self.sources[unique_source.source_index]
+            .source
+            .parsed_source_map_chain()
+        })
+        .collect::<Vec<_>>();
+      let mut extensions = ExtensionsCollector::new(&opts.extension_fields_policy);
+      let tokens = map
+        .tokens()
+        .filter(|token| token.has_source() && (token.get_src_id() as usize) < emitted_sources.len())
+        .collect::<Vec<_>>();
+
+      let traced_tokens = trace_tokens(
+        &tokens,
+        |token| &emitted_source_map_chains[token.get_src_id() as usize],
+        opts.lookup_bias,
+        true,
+        opts.trace_threads,
+      );
+
+      for traced_line in
+        traced_tokens.chunk_by(|a, b| a.token.get_dst_line() == b.token.get_dst_line())
+      {
+        for (traced, mapping) in traced_line.iter().zip(resolve_traced_line(
+          traced_line,
+          opts.untraceable_token_strategy,
+        )) {
+          let token = traced.token;
+          let emitted_source = &emitted_sources[token.get_src_id() as usize];
+
+          if !emitted_source.trace_source_map_chain {
+            let added_token = trace_sourcemap_builder.add_token(&token, false);
+
+            if let Some(view) = token
+              .get_source_view()
+              .filter(|_| emitted_source.include_content)
+            {
+              trace_sourcemap_builder.set_source_contents(added_token.src_id, Some(view.source()));
+            }
+
+            if emitted_source.is_ignored(token.get_source().unwrap_or_default())
+              && !ignored_src_ids.contains(&added_token.src_id)
+            {
+              ignored_src_ids.push(added_token.src_id);
+            }
+
+            continue;
+          }
+
+          let (map_token, name) = match mapping {
+            TracedMapping::Token(location) => (location, location.get_name()),
+            TracedMapping::Location(location) => (location, None),
+            TracedMapping::Unmapped => {
+              trace_sourcemap_builder.add_raw(
+                token.get_dst_line(),
+                token.get_dst_col(),
+                0,
+                0,
+                None,
+                None,
+                false,
+              );
+              continue;
+            }
+            TracedMapping::Dropped => continue,
+          };
+
+          // a token that could not be traced through the first map of the chain keeps the final source of this map
+          let src = if std::ptr::eq(map_token.token.sourcemap(), &map) {
+            map_token.token.get_source().map(ToString::to_string)
+          } else {
+            resolve_token_source(&map_token.token).map(|src| {
+              if let Some(remap_source) = &opts.remap_source {
+                mapped_src_cache
+                  .entry(src)
+                  .or_insert_with_key(|src| remap_source(src))
+                  .to_string()
+              } else {
+                src
+              }
+            })
+          };
+
+          let added_token = trace_sourcemap_builder.add(
+            token.get_dst_line(),
+            token.get_dst_col(),
+            map_token.get_src_line(),
+            map_token.get_src_col(),
+            src.as_deref(),
+            name,
+            false,
+          );
+
+          if emitted_source.is_ignored(src.as_deref().unwrap_or_default())
+            && !ignored_src_ids.contains(&added_token.src_id)
+          {
+            ignored_src_ids.push(added_token.src_id);
+          }
+
+          extensions.add_source(
+            added_token.src_id,
+            &map_token.token,
+            &emitted_source_map_chains[token.get_src_id() as usize],
+          );
+
+          if emitted_source.include_content
+            && !trace_sourcemap_builder.has_source_contents(added_token.src_id)
+          {
+            let source_content = read_source_content(
+              map_token.token,
+              map_token.token.sourcemap(),
+              resolver_or_fs(&opts.source_resolver),
+            );
+
+            if let Some(source_content) = source_content {
+              trace_sourcemap_builder
+                .set_source_contents(added_token.src_id, Some(&source_content));
+            }
+          }
+        }
+      }
+
+      let mut traced_map = GeneratedSourceMap::new(trace_sourcemap_builder.into_sourcemap())
+        .with_line_count(line_count)
+        .with_source_root(opts.source_root.clone());
+      ignored_src_ids
+        .into_iter()
+        .for_each(|src_id| traced_map.add_to_ignore_list(src_id));
+      extensions.apply(&mut traced_map);
+
+      return Ok(traced_map.with_extension_fields(&opts.extension_fields));
+    }
+
+    // the emitted sources of the same filename become one source of the map
+    let mut src_ids = vec![];
+    let mut src_id_by_filename: HashMap<Arc<str>, u32> = HashMap::new();
+    let mut unique_sources = vec![];
+    let mut unique_sources_content = vec![];
+
+    for ((source, content), emitted_source) in sources
+      .into_iter()
+      .zip(sources_content)
+      .zip(&emitted_sources)
+    {
+      let src_id = *src_id_by_filename.entry(source.clone()).or_insert_with(|| {
+        unique_sources.push(source);
+        unique_sources_content.push(content.filter(|_| emitted_source.include_content));
+        unique_sources.len() as u32 - 1
+      });
+      src_ids.push(src_id);
+    }
+
+    mappings.map_source_indexes(|src_id| src_ids[src_id as usize]);
+
+    let mut map = GeneratedSourceMap::from_mappings(
+      mappings,
+      opts.file.as_deref(),
+      unique_sources,
+      unique_sources_content,
+      unique_names,
+    )
+    .with_source_root(opts.source_root.clone());
+    let ignored_src_ids = emitted_sources
+      .iter()
+      .zip(&src_ids)
+      .filter(|(emitted_source, src_id)| {
+        emitted_source.is_ignored(map.get_source(**src_id).unwrap_or_default())
+      })
+      .map(|(_, src_id)| *src_id)
+      .collect::<Vec<_>>();
+    ignored_src_ids
+      .into_iter()
+      .for_each(|src_id| map.add_to_ignore_list(src_id));
+
+    Ok(map.with_extension_fields(&opts.extension_fields))
+  }
+
+  /// Emit the pieces of the intro and the sources, in output order.
+  fn walk(&self, source_indexes: &[usize], sink: &mut impl PieceSink) {
+    sink.text(TextKind::Bundle, self.intro.as_slice());
+
+    source_indexes.iter().enumerate().for_each(|(i, index)| {
+      let bundle_source = &self.sources[*index];
+      let source = &bundle_source.source;
+
+      if i > 0 {
+        if let Some(separator) = &bundle_source.separator {
+          sink.text(TextKind::Separator, separator.as_slice());
+        }
+      }
+
+      sink.begin_source(*index);
+      sink.text(TextKind::Wrapper, bundle_source.intro.as_slice());
+      sink.text(TextKind::Wrapper, source.intro.as_slice());
+
+      source.first_chunk.lock().each_next(|chunk| {
+        sink.text(TextKind::Insertion, chunk.intro.as_slice());
+
+        if chunk.edited {
+          sink.edited_text(*index, source, chunk.start, chunk.content.as_slice());
+        } else {
+          sink.source_text(*index, source, chunk.start, chunk.end);
+        }
+
+        sink.text(TextKind::Insertion, chunk.outro.as_slice());
+      });
+
+      sink.text(TextKind::Wrapper, source.outro.as_slice());
+      sink.text(TextKind::Wrapper, bundle_source.outro.as_slice());
+      sink.end_source(*index);
+    });
+  }
+
+  /// Emit the pieces of the whole output: trimmed and indented content wrapped by [Bundle::wrap].
+  /// Trimming the end walks the sources twice, the first walk only finds where the trailing whitespace starts.
+  fn write_output(&self, source_indexes: &[usize], sink: &mut impl PieceSink) {
+    sink.text(TextKind::Bundle, self.wrap_before.as_slice());
+
+    if self.indent_str.is_none() && !self.trim_start && !self.trim_end {
+      self.walk(source_indexes, sink);
+    } else {
+      let content_end = if self.trim_end {
+        let mut finder = TrimEndFinder::default();
+        self.walk(source_indexes, &mut finder);
+        finder.content_end
+      } else {
+        usize::MAX
+      };
+      let at_line_start = self
+        .wrap_before
+        .as_slice()
+        .last()
+        .is_none_or(|char| *char == '\n');
+      let mut filter = IndentTrimFilter::new(
+        sink,
+        self.indent_str.as_ref(),
+        self.trim_start,
+        content_end,
+        at_line_start,
+      );
+      self.walk(source_indexes, &mut filter);
+    }
+
+    sink.text(TextKind::Bundle, self.wrap_after.as_slice());
+  }
+
+  fn write_sources_to<W: Write + ?Sized>(
+    &self,
+    source_indexes: &[usize],
+    writer: &mut W,
+  ) -> std::io::Result<()> {
+    let mut write_sink = WriteSink::new(writer);
+    self.write_output(source_indexes, &mut write_sink);
+
+    write_sink.finish()
+  }
+
+  fn fmt_sources(
+    &self,
+    source_indexes: &[usize],
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    let mut fmt_sink = FmtSink::new(f);
+    self.write_output(source_indexes, &mut fmt_sink);
+
+    fmt_sink.result
+  }
+
+  /// Write the generated code to `writer` without building it in memory first, e.g. to a file or a hasher.
+  /// The code is written piece by piece, wrap `writer` in a [std::io::BufWriter] if writes are expensive.
+  pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> std::io::Result<()> {
+    let source_indexes = (0..self.sources.len()).collect::<Vec<_>>();
+
+    self.write_sources_to(&source_indexes, writer)
+  }
+
+  pub fn append(&mut self, str: &str, opts: Option<AddSourceOptions>) {
+    let mut opts = opts.unwrap_or_default();
+    opts.separator.get_or_insert_with(|| "".into());
+
+    self
+      .add_bundle_source(Arc::new(MagicString::new(str, None)), Some(opts), false)
+      .unwrap();
+  }
+
+  pub fn prepend(&mut self, str: &str) {
+    let mut new_intro = CharString::new(str);
+    new_intro.append(&self.intro);
+    self.intro = new_intro;
+  }
+
+  /// The most common indent string of the sources, defaults to a tab.
+  pub fn get_indent_string(&self) -> CharString {
+    let mut indent_str_counts: Vec<(CharString, usize)> = vec![];
+
+    self.sources.iter().for_each(|bundle_source| {
+      if let Some(indent_str) = bundle_source.source.get_raw_indent_string() {
+        match indent_str_counts
+          .iter_mut()
+          .find(|(existing, _)| *existing == indent_str)
+        {
+          Some((_, count)) => *count += 1,
+          None => indent_str_counts.push((indent_str, 1)),
+        }
+      }
+    });
+
+    indent_str_counts
+      .into_iter()
+      .rev()
+      .max_by_key(|(_, count)| *count)
+      .map(|(indent_str, _)| indent_str)
+      .unwrap_or_else(|| CharString::from('\t'))
+  }
+
+  /// Indent every non-empty line of the intro and the sources, chars in the `indent_exclusion_ranges` of a source are not indented.
+  /// The indent string is guessed from the sources if `indent_str` is `None`, see [Bundle::get_indent_string].
+  /// The source maps are adjusted for the inserted indent. The shared sources are not modified.
+  /// Indenting multiple times nests the indents, like magic-string.
+  pub fn indent(&mut self, indent_str: Option<&str>) {
+    let mut indent_str = indent_str
+      .map(CharString::new)
+      .unwrap_or_else(|| self.get_indent_string());
+
+    if let Some(existing) = &self.indent_str {
+      indent_str.append(existing);
+    }
+
+    self.indent_str = indent_str.non_empty();
+  }
+
+  /// Wrap the whole output, e.g. in an IIFE or UMD shell. Wrapping is not affected by [Bundle::indent] and [Bundle::trim],
+  /// and wrapping multiple times nests the wrappers.
+  pub fn wrap(&mut self, before: &str, after: &str) {
+    let mut new_wrap_before = CharString::new(before);
+    new_wrap_before.append(&self.wrap_before);
+    self.wrap_before = new_wrap_before;
+    self.wrap_after.append_str(after);
+  }
+
+  /// Trim leading and trailing whitespace of the intro and sources.
+  pub fn trim(&mut self) {
+    self.trim_start();
+    self.trim_end();
+  }
+
+  pub fn trim_start(&mut self) {
+    self.trim_start = true;
+  }
+
+  pub fn trim_end(&mut self) {
+    self.trim_end = true;
+  }
+}
+
+impl Display for Bundle {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let source_indexes = (0..self.sources.len()).collect::<Vec<_>>();
+
+    self.fmt_sources(&source_indexes, f)
+  }
+}
+
+/// One output of a split [Bundle], see [Bundle::split].
+pub struct BundleOutput<'a> {
+  bundle: &'a Bundle,
+  source_indexes: Vec<usize>,
+}
+
+impl BundleOutput<'_> {
+  /// Indexes of the sources emitted by this output, in emitting order.
+  pub fn source_indexes(&self) -> &[usize] {
+    &self.source_indexes
+  }
+
+  /// See [Bundle::generate_map].
+  pub fn generate_map(&self, opts: SourceMapOptions) -> Result<GeneratedSourceMap> {
+    self
+      .bundle
+      .generate_map_of_sources(&self.source_indexes, opts)
+  }
+
+  pub fn manifest(&self, opts: SourceMapOptions) -> Result<BundleManifest> {
+    self.bundle.manifest_of_sources(&self.source_indexes, opts)
+  }
+
+  /// See [Bundle::generate_decoded_map].
+  pub fn generate_decoded_map(&self, opts: SourceMapOptions) -> Result<DecodedSourceMap> {
+    Ok(self.generate_map(opts)?.to_decoded())
+  }
+
+  /// See [Bundle::write_to].
+  pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> std::io::Result<()> {
+    self.bundle.write_sources_to(&self.source_indexes, writer)
+  }
+
+  /// See [Bundle::generate].
+  pub fn generate(&self, opts: SourceMapOptions) -> Result<GeneratedCode> {
+    generate_code(opts, |code_sink, opts| {
+      self
+        .bundle
+        .generate_of_sources(&self.source_indexes, opts, code_sink)
+    })
+  }
+
+  /// See [Bundle::generate_to_writer].
+  pub fn generate_to_writer<W: Write>(
+    &self,
+    writer: W,
+    opts: SourceMapOptions,
+  ) -> Result<GeneratedSourceMap> {
+    generate_to_writer(writer, opts, |code_sink, opts| {
+      self
+        .bundle
+        .generate_of_sources(&self.source_indexes, opts, code_sink)
+    })
+  }
+}
+
+impl Display for BundleOutput<'_> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    self.bundle.fmt_sources(&self.source_indexes, f)
+  }
+}
+
+/// Collects the mappings of the pieces of a bundle output.
+struct MappingsSink<'a> {
+  mappings: Mappings,
+  hires: MappingsOptionHires,
+  sources: &'a [BundleSource],
+  unique_sources: &'a [UniqueSource],
+  source_index_by_unique_index: &'a HashMap<usize, usize>,
+  /// locator of the source that is being emitted
+  locator: Option<(usize, Locator)>,
+}
+
+impl PieceSink for MappingsSink<'_> {
+  fn text(&mut self, _: TextKind, text: &[char]) {
+    self.mappings.advance(text);
+  }
+
+  fn source_text(&mut self, source_index: usize, source: &MagicString, start: usize, end: usize) {
+    let Some((unique_index, loc)) = self.locate(source_index, source, start) else {
+      self
+        .mappings
+        .advance(&source.original.as_slice()[start..end]);
+      return;
+    };
+
+    self.mappings.add_unedited_range(
+      unique_index,
+      start,
+      end,
+      &source.original,
+      loc,
+      &source.sourcemap_locations,
+    );
+  }
+
+  fn edited_text(
+    &mut self,
+    source_index: usize,
+    source: &MagicString,
+    start: usize,
+    content: &[char],
+  ) {
+    match self.locate(source_index, source, start) {
+      Some((unique_index, loc)) => self.mappings.add_edit(unique_index, content, loc),
+      None => self.mappings.advance(content),
+    }
+  }
+}
+
+impl MappingsSink<'_> {
+  /// Index of the emitted source and location of `start` in the bundle source at `source_index`, `None` if the
+  /// source is anonymous.
+  fn locate(
+    &mut self,
+    source_index: usize,
+    source: &MagicString,
+    start: usize,
+  ) -> Option<(usize, Loc)> {
+    let bundle_source = &self.sources[source_index];
+    let unique_index = bundle_source.unique_source_index?;
+
+    if !matches!(&self.locator, Some((index, _)) if *index == source_index) {
+      self.mappings.set_hires(
+        bundle_source.hires.as_ref().unwrap_or(&self.hires),
+        source,
+        Some(&self.unique_sources[unique_index].added_filename),
+      );
+      self.locator = Some((source_index, Box::new(get_locator(&source.original))));
+    }
+
+    let loc = (self.locator.as_ref().unwrap().1)(start);
+
+    Some((self.source_index_by_unique_index[&unique_index], loc))
+  }
+}
+
+fn generate_code(
+  mut opts: SourceMapOptions,
+  generate: impl FnOnce(&mut String, SourceMapOptions) -> Result<GeneratedSourceMap>,
+) -> Result<GeneratedCode> {
+  let url_comment = opts.url_comment.take();
+  let mut code = String::new();
+  let map = generate(&mut code, opts)?;
+
+  if let Some(url_comment) = url_comment {
+    code.push('\n');
+    code.push_str(&map.to_comment(&url_comment));
+  }
+
+  Ok(GeneratedCode { code, map })
+}
+
+fn generate_to_writer<W: Write>(
+  writer: W,
+  mut opts: SourceMapOptions,
+  generate: impl FnOnce(&mut WriteSink<W>, SourceMapOptions) -> Result<GeneratedSourceMap>,
+) -> Result<GeneratedSourceMap> {
+  let url_comment = opts.url_comment.take();
+  let mut write_sink = WriteSink::new(writer);
+  let map = generate(&mut write_sink, opts)?;
+
+  if let Some(url_comment) = url_comment {
+    let comment = format!("\n{}", map.to_comment(&url_comment));
+    write_sink.text(TextKind::Bundle, &comment.chars().collect::<Vec<_>>());
+  }
+
+  write_sink.finish()?;
+
+  Ok(map)
+}
+
+fn source_byte_len(source: &MagicString) -> usize {
+  let mut len = source.intro.byte_len() + source.outro.byte_len();
+
+  source.first_chunk.lock().each_next(|chunk| {
+    len += chunk.intro.byte_len() + chunk.content.byte_len() + chunk.outro.byte_len();
+  });
+
+  len
+}
+
+fn separator_byte_len(separator: &Option<CharString>) -> usize {
+  separator
+    .as_ref()
+    .map(|separator| separator.byte_len())
+    .unwrap_or(0)
+}
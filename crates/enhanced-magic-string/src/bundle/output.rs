@@ -0,0 +1,348 @@
+use std::{fmt::Write as _, io::Write};
+
+use crate::{
+  magic_string::MagicString,
+  utils::char_string::{write_chars, CharString},
+};
+
+/// Where a generated text that does not come from the content of a source belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TextKind {
+  /// intro of the bundle and [super::Bundle::wrap]
+  Bundle,
+  Separator,
+  /// intro and outro of a source, including the ones set by [super::AddSourceOptions]
+  Wrapper,
+  /// intro and outro of chunks, and edited chunks
+  Insertion,
+  /// inserted by [super::Bundle::indent]
+  Indent,
+}
+
+/// Receives the generated code of a bundle piece by piece, in output order.
+pub(crate) trait PieceSink {
+  /// Generated text that does not come from the content of a source.
+  fn text(&mut self, kind: TextKind, text: &[char]);
+
+  /// Unedited text `source.original[start..end]` of the bundle source at `source_index`.
+  fn source_text(&mut self, source_index: usize, source: &MagicString, start: usize, end: usize);
+
+  /// `content` of an edited chunk of the bundle source at `source_index`, the chunk starts at `start` of the original.
+  fn edited_text(
+    &mut self,
+    _source_index: usize,
+    _source: &MagicString,
+    _start: usize,
+    content: &[char],
+  ) {
+    self.text(TextKind::Insertion, content);
+  }
+
+  /// Called after the separator and before anything else of the bundle source at `source_index`.
+  fn begin_source(&mut self, _source_index: usize) {}
+
+  /// Called after everything of the bundle source at `source_index`.
+  fn end_source(&mut self, _source_index: usize) {}
+}
+
+impl PieceSink for String {
+  fn text(&mut self, _: TextKind, text: &[char]) {
+    self.extend(text);
+  }
+
+  fn source_text(&mut self, _: usize, source: &MagicString, start: usize, end: usize) {
+    self.extend(&source.original.as_slice()[start..end]);
+  }
+}
+
+/// Discards the pieces.
+impl PieceSink for () {
+  fn text(&mut self, _: TextKind, _: &[char]) {}
+
+  fn source_text(&mut self, _: usize, _: &MagicString, _: usize, _: usize) {}
+}
+
+/// Passes every piece to both sinks.
+pub(crate) struct TeeSink<'a, A: PieceSink, B: PieceSink> {
+  pub a: &'a mut A,
+  pub b: &'a mut B,
+}
+
+impl<A: PieceSink, B: PieceSink> PieceSink for TeeSink<'_, A, B> {
+  fn text(&mut self, kind: TextKind, text: &[char]) {
+    self.a.text(kind, text);
+    self.b.text(kind, text);
+  }
+
+  fn source_text(&mut self, source_index: usize, source: &MagicString, start: usize, end: usize) {
+    self.a.source_text(source_index, source, start, end);
+    self.b.source_text(source_index, source, start, end);
+  }
+
+  fn edited_text(
+    &mut self,
+    source_index: usize,
+    source: &MagicString,
+    start: usize,
+    content: &[char],
+  ) {
+    self.a.edited_text(source_index, source, start, content);
+    self.b.edited_text(source_index, source, start, content);
+  }
+
+  fn begin_source(&mut self, source_index: usize) {
+    self.a.begin_source(source_index);
+    self.b.begin_source(source_index);
+  }
+
+  fn end_source(&mut self, source_index: usize) {
+    self.a.end_source(source_index);
+    self.b.end_source(source_index);
+  }
+}
+
+/// Writes the pieces to a [Write] as utf-8. Pieces after the first failed write are skipped,
+/// the error is returned by [WriteSink::finish].
+pub(crate) struct WriteSink<W: Write> {
+  writer: W,
+  error: Option<std::io::Error>,
+}
+
+impl<W: Write> WriteSink<W> {
+  pub fn new(writer: W) -> Self {
+    Self {
+      writer,
+      error: None,
+    }
+  }
+
+  fn write(&mut self, chars: &[char]) {
+    if self.error.is_some() || chars.is_empty() {
+      return;
+    }
+
+    if let Err(e) = write_chars(&mut self.writer, chars) {
+      self.error = Some(e);
+    }
+  }
+
+  pub fn finish(mut self) -> std::io::Result<()> {
+    match self.error.take() {
+      Some(e) => Err(e),
+      None => self.writer.flush(),
+    }
+  }
+}
+
+impl<W: Write> PieceSink for WriteSink<W> {
+  fn text(&mut self, _: TextKind, text: &[char]) {
+    self.write(text);
+  }
+
+  fn source_text(&mut self, _: usize, source: &MagicString, start: usize, end: usize) {
+    self.write(&source.original.as_slice()[start..end]);
+  }
+}
+
+/// Writes the pieces to a [std::fmt::Formatter], pieces after the first error are skipped.
+pub(crate) struct FmtSink<'a, 'b> {
+  f: &'a mut std::fmt::Formatter<'b>,
+  pub result: std::fmt::Result,
+}
+
+impl<'a, 'b> FmtSink<'a, 'b> {
+  pub fn new(f: &'a mut std::fmt::Formatter<'b>) -> Self {
+    Self { f, result: Ok(()) }
+  }
+
+  fn write(&mut self, chars: &[char]) {
+    if self.result.is_ok() {
+      self.result = chars.iter().try_for_each(|char| self.f.write_char(*char));
+    }
+  }
+}
+
+impl PieceSink for FmtSink<'_, '_> {
+  fn text(&mut self, _: TextKind, text: &[char]) {
+    self.write(text);
+  }
+
+  fn source_text(&mut self, _: usize, source: &MagicString, start: usize, end: usize) {
+    self.write(&source.original.as_slice()[start..end]);
+  }
+}
+
+/// Finds where the content ends once trailing whitespace is trimmed.
+#[derive(Default)]
+pub(crate) struct TrimEndFinder {
+  pos: usize,
+  /// number of chars before the last non-whitespace char, inclusive
+  pub content_end: usize,
+}
+
+impl TrimEndFinder {
+  fn visit(&mut self, chars: &[char]) {
+    for char in chars {
+      self.pos += 1;
+
+      if !char.is_whitespace() {
+        self.content_end = self.pos;
+      }
+    }
+  }
+}
+
+impl PieceSink for TrimEndFinder {
+  fn text(&mut self, _: TextKind, text: &[char]) {
+    self.visit(text);
+  }
+
+  fn source_text(&mut self, _: usize, source: &MagicString, start: usize, end: usize) {
+    self.visit(&source.original.as_slice()[start..end]);
+  }
+}
+
+enum Run {
+  Indent,
+  Range(usize, usize),
+}
+
+/// Trims and indents the pieces passed to the inner sink.
+/// Indent is inserted before the first char of every non-empty line, except for the chars in the
+/// `indent_exclusion_ranges` of a source.
+pub(crate) struct IndentTrimFilter<'a, S: PieceSink> {
+  sink: &'a mut S,
+  indent_str: Option<&'a CharString>,
+  at_line_start: bool,
+  trim_start: bool,
+  pos: usize,
+  content_end: usize,
+}
+
+impl<'a, S: PieceSink> IndentTrimFilter<'a, S> {
+  /// `content_end` is the number of chars to keep, chars after it are trimmed. `at_line_start` is whether the text
+  /// emitted before the filter, like the wrapper of the bundle, ends a line.
+  pub fn new(
+    sink: &'a mut S,
+    indent_str: Option<&'a CharString>,
+    trim_start: bool,
+    content_end: usize,
+    at_line_start: bool,
+  ) -> Self {
+    Self {
+      sink,
+      indent_str,
+      at_line_start,
+      trim_start,
+      pos: 0,
+      content_end,
+    }
+  }
+
+  fn runs(&mut self, chars: &[char], is_excluded: impl Fn(usize) -> bool) -> Vec<Run> {
+    let mut runs = vec![];
+    let mut run_start = None;
+
+    for (index, char) in chars.iter().enumerate() {
+      let pos = self.pos;
+      self.pos += 1;
+
+      if pos >= self.content_end || (self.trim_start && char.is_whitespace()) {
+        if let Some(start) = run_start.take() {
+          runs.push(Run::Range(start, index));
+        }
+        continue;
+      }
+
+      self.trim_start = false;
+
+      if self.indent_str.is_some()
+        && self.at_line_start
+        && *char != '\n'
+        && *char != '\r'
+        && !is_excluded(index)
+      {
+        if let Some(start) = run_start.take() {
+          runs.push(Run::Range(start, index));
+        }
+        runs.push(Run::Indent);
+      }
+
+      self.at_line_start = *char == '\n';
+      run_start.get_or_insert(index);
+    }
+
+    if let Some(start) = run_start {
+      runs.push(Run::Range(start, chars.len()));
+    }
+
+    runs
+  }
+}
+
+impl<S: PieceSink> PieceSink for IndentTrimFilter<'_, S> {
+  fn text(&mut self, kind: TextKind, text: &[char]) {
+    for run in self.runs(text, |_| false) {
+      match run {
+        Run::Indent => self
+          .sink
+          .text(TextKind::Indent, self.indent_str.unwrap().as_slice()),
+        Run::Range(start, end) => self.sink.text(kind, &text[start..end]),
+      }
+    }
+  }
+
+  fn source_text(&mut self, source_index: usize, source: &MagicString, start: usize, end: usize) {
+    let chars = &source.original.as_slice()[start..end];
+    let is_excluded = |index: usize| {
+      source
+        .indent_exclusion_ranges
+        .iter()
+        .any(|(exclusion_start, exclusion_end)| {
+          (*exclusion_start..*exclusion_end).contains(&(start + index))
+        })
+    };
+
+    for run in self.runs(chars, is_excluded) {
+      match run {
+        Run::Indent => self
+          .sink
+          .text(TextKind::Indent, self.indent_str.unwrap().as_slice()),
+        Run::Range(run_start, run_end) => {
+          self
+            .sink
+            .source_text(source_index, source, start + run_start, start + run_end)
+        }
+      }
+    }
+  }
+
+  fn edited_text(
+    &mut self,
+    source_index: usize,
+    source: &MagicString,
+    start: usize,
+    content: &[char],
+  ) {
+    for run in self.runs(content, |_| false) {
+      match run {
+        Run::Indent => self
+          .sink
+          .text(TextKind::Indent, self.indent_str.unwrap().as_slice()),
+        Run::Range(run_start, run_end) => {
+          self
+            .sink
+            .edited_text(source_index, source, start, &content[run_start..run_end])
+        }
+      }
+    }
+  }
+
+  fn begin_source(&mut self, source_index: usize) {
+    self.sink.begin_source(source_index);
+  }
+
+  fn end_source(&mut self, source_index: usize) {
+    self.sink.end_source(source_index);
+  }
+}
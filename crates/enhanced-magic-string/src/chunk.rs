@@ -1,4 +1,4 @@
-use std::ptr::NonNull;
+use std::{fmt::Display, ptr::NonNull};
 
 use parking_lot::Mutex;
 
@@ -59,7 +59,7 @@ impl Chunk {
     let mut chunk = Some(self);
 
     while let Some(c) = chunk {
-      f(&c);
+      f(c);
       chunk = c.next();
     }
   }
@@ -104,6 +104,7 @@ impl Chunk {
     }
   }
 
+  #[allow(clippy::mut_from_ref)]
   pub fn previous_mut(&self) -> Option<&mut Chunk> {
     match self.previous.lock().as_mut() {
       Some(previous) => {
@@ -126,9 +127,9 @@ impl Chunk {
   }
 }
 
-impl ToString for Chunk {
-  fn to_string(&self) -> String {
-    format!("{}{}{}", self.intro, self.content, self.outro)
+impl Display for Chunk {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}{}{}", self.intro, self.content, self.outro)
   }
 }
 
@@ -0,0 +1,190 @@
+use std::ops::Range;
+
+use sourcemap::{SourceMap, Token};
+
+use crate::types::LookupBias;
+
+use super::lookup_token_with_bias;
+
+/// The range of tokens of each generated line of a map, so a lookup only searches the tokens of its line.
+/// Lookups give the same tokens as [lookup_token_with_bias].
+#[derive(Debug, Clone)]
+pub(crate) struct LineIndex {
+  /// index of the first token of each line, plus the token count at the end. Empty if the tokens are not sorted by
+  /// line, then the lookups fall back to [lookup_token_with_bias].
+  line_starts: Vec<u32>,
+}
+
+impl LineIndex {
+  pub fn new(map: &SourceMap) -> Self {
+    let mut line_starts: Vec<u32> = vec![0];
+    let mut last_line = 0;
+
+    for (index, token) in map.tokens().enumerate() {
+      let line = token.get_dst_line();
+
+      if line < last_line {
+        return Self {
+          line_starts: vec![],
+        };
+      }
+
+      while line_starts.len() <= line as usize {
+        line_starts.push(index as u32);
+      }
+
+      last_line = line;
+    }
+
+    line_starts.push(map.get_token_count());
+
+    Self { line_starts }
+  }
+
+  /// Token indexes of `line`.
+  fn line(&self, line: u32) -> Range<usize> {
+    let line = line as usize;
+
+    match (self.line_starts.get(line), self.line_starts.get(line + 1)) {
+      (Some(start), Some(end)) => *start as usize..*end as usize,
+      // after the last line
+      _ => {
+        let count = *self.line_starts.last().unwrap_or(&0) as usize;
+        count..count
+      }
+    }
+  }
+
+  pub fn lookup<'a>(
+    &self,
+    map: &'a SourceMap,
+    line: u32,
+    col: u32,
+    bias: LookupBias,
+  ) -> Option<Token<'a>> {
+    if self.line_starts.is_empty() {
+      return lookup_token_with_bias(map, line, col, bias);
+    }
+
+    let tokens = self.line(line);
+    // the first token of the line at or after `col`
+    let first_at =
+      tokens.start + partition_point(map, tokens.clone(), |token| token.get_dst_col() < col);
+    let token_at = (first_at < tokens.end)
+      .then(|| map.get_token(first_at))
+      .flatten();
+
+    let token = match bias {
+      LookupBias::LeastUpperBound => return token_at,
+      LookupBias::Exact => token_at.filter(|token| token.get_dst_col() == col),
+      LookupBias::GreatestLowerBound => match token_at {
+        Some(token) if token.get_dst_col() == col => Some(token),
+        _ if first_at > tokens.start => map.get_token(first_at - 1),
+        _ => self.lookup_before_line(map, line, tokens),
+      },
+    };
+
+    match token {
+      // sourcemap offsets the source column of a range token by the lookup column
+      Some(token) if token.is_range() => lookup_token_with_bias(map, line, col, bias),
+      token => token,
+    }
+  }
+
+  /// Like [super::lookup_token] for a `(line, col)` before the first token of its line.
+  fn lookup_before_line<'a>(
+    &self,
+    map: &'a SourceMap,
+    line: u32,
+    tokens: Range<usize>,
+  ) -> Option<Token<'a>> {
+    let previous = map.get_token(tokens.start.checked_sub(1)?)?;
+
+    if line > 0
+      && previous.get_dst_line() == line - 1
+      && previous.get_dst_col() > 0
+      && !tokens.is_empty()
+    {
+      let next_line = self.line(line + 1);
+      let next_line_starts_at_0 = !next_line.is_empty()
+        && map
+          .get_token(next_line.start)
+          .is_some_and(|token| token.get_dst_col() == 0);
+
+      if !next_line_starts_at_0 {
+        return map.get_token(tokens.end - 1);
+      }
+    }
+
+    Some(previous)
+  }
+}
+
+/// Number of the tokens in `tokens` for which `pred` holds, the tokens must be partitioned by it.
+fn partition_point(map: &SourceMap, tokens: Range<usize>, pred: impl Fn(&Token) -> bool) -> usize {
+  let (mut low, mut high) = (0, tokens.len());
+
+  while low < high {
+    let mid = low + (high - low) / 2;
+
+    if map
+      .get_token(tokens.start + mid)
+      .is_some_and(|token| pred(&token))
+    {
+      low = mid + 1;
+    } else {
+      high = mid;
+    }
+  }
+
+  low
+}
+
+#[cfg(test)]
+mod tests {
+  use sourcemap::SourceMapBuilder;
+
+  use super::*;
+
+  #[test]
+  fn test_lookup() {
+    let mut builder = SourceMapBuilder::new(None);
+    let src_id = builder.add_source("a.js");
+    // empty lines, a line starting after column 0, duplicated columns and a line starting at column 0
+    for (index, (line, col)) in [
+      (0, 0),
+      (0, 5),
+      (2, 3),
+      (2, 3),
+      (2, 8),
+      (3, 0),
+      (3, 4),
+      (5, 2),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+      builder.add_raw(line, col, index as u32, 0, Some(src_id), None, false);
+    }
+    let map = builder.into_sourcemap();
+    let index = LineIndex::new(&map);
+
+    for bias in [
+      LookupBias::GreatestLowerBound,
+      LookupBias::LeastUpperBound,
+      LookupBias::Exact,
+    ] {
+      for line in 0..8 {
+        for col in 0..10 {
+          assert_eq!(
+            index
+              .lookup(&map, line, col, bias)
+              .map(|token| token.get_src()),
+            lookup_token_with_bias(&map, line, col, bias).map(|token| token.get_src()),
+            "{bias:?} {line}:{col}"
+          );
+        }
+      }
+    }
+  }
+}
@@ -1,17 +1,47 @@
 use std::{
   cell::{RefCell, RefMut},
-  path::PathBuf,
+  collections::HashMap,
+  path::{Path, PathBuf},
+  sync::Arc,
 };
 
 use farmfe_utils::file_url_to_path;
+use serde_json::Value;
 use sourcemap::{SourceMap, SourceMapBuilder, Token};
 
+use crate::{
+  source_map::{ChainSourceMap, GeneratedSourceMap},
+  source_resolver::{resolver_or_fs, SourceResolver},
+  types::{ExtensionFieldsPolicy, LookupBias, RemapSource, UntraceableTokenStrategy},
+  utils::source_path,
+};
+
+pub(crate) use line_index::LineIndex;
+
+mod line_index;
+
 pub struct CollapseSourcemapOptions {
   /// if true, inline source content to the source map.
-  /// if the source content does not exist and source filename exists, content will be read from source file from disk.
+  /// if the source content does not exist and source filename exists, content will be read by `source_resolver`.
   pub inline_content: bool,
 
-  pub remap_source: Option<Box<dyn Fn(&str) -> String>>,
+  pub remap_source: Option<RemapSource>,
+
+  /// supplies the source content that is not inlined in the maps, reads from disk if `None`.
+  pub source_resolver: Option<Arc<dyn SourceResolver>>,
+
+  /// How the tokens are looked up in the maps of the chain.
+  pub lookup_bias: LookupBias,
+
+  /// How to map the tokens that can not be traced back to the first map of the chain.
+  pub untraceable_token_strategy: UntraceableTokenStrategy,
+
+  /// What happens to the `x_*` fields of the maps of the chain, used by [collapse_sourcemap_chain_with_extensions].
+  pub extension_fields_policy: ExtensionFieldsPolicy,
+
+  /// Number of threads that trace the tokens through the chain, `None` means the available parallelism.
+  /// Only large maps are split across threads, `Some(1)` always traces on the calling thread.
+  pub trace_threads: Option<usize>,
 }
 
 impl Default for CollapseSourcemapOptions {
@@ -19,6 +49,11 @@ impl Default for CollapseSourcemapOptions {
     Self {
       inline_content: true,
       remap_source: None,
+      source_resolver: None,
+      lookup_bias: LookupBias::default(),
+      untraceable_token_strategy: UntraceableTokenStrategy::default(),
+      extension_fields_policy: ExtensionFieldsPolicy::default(),
+      trace_threads: None,
     }
   }
 }
@@ -31,92 +66,461 @@ impl Default for CollapseSourcemapOptions {
 /// now we have d and map_d, we want to get a and map_a, we should tracing from map_d to map_a.
 ///
 pub fn collapse_sourcemap_chain(
-  mut chain: Vec<SourceMap>,
+  chain: Vec<SourceMap>,
   opts: CollapseSourcemapOptions,
 ) -> SourceMap {
+  collapse_sourcemap_chain_with_extensions(chain.into_iter().map(Into::into).collect(), opts)
+    .into_inner()
+}
+
+/// Like [collapse_sourcemap_chain], and carries `x_google_ignoreList` and the `x_*` fields of the maps that the
+/// sources come from forward, see [CollapseSourcemapOptions::extension_fields_policy].
+pub fn collapse_sourcemap_chain_with_extensions(
+  mut chain: Vec<ChainSourceMap>,
+  opts: CollapseSourcemapOptions,
+) -> GeneratedSourceMap {
   chain.reverse();
-  chain = chain
-    .into_iter()
-    .filter(|map| map.get_token_count() > 0)
-    .collect();
+  chain.retain(|map| map.get_token_count() > 0);
 
   if chain.is_empty() {
     let builder = SourceMapBuilder::new(None);
-    return builder.into_sourcemap();
+    return GeneratedSourceMap::new(builder.into_sourcemap());
   }
 
   let dest_map = &chain[0];
   let mut builder = SourceMapBuilder::new(None);
   let mut mapped_src_cache = std::collections::HashMap::new();
+  let tokens = dest_map.tokens().collect::<Vec<_>>();
+  let mut extensions = ExtensionsCollector::new(&opts.extension_fields_policy);
 
   // trace all tokens in cur and update
-  for token in dest_map.tokens() {
-    let mut last_map_token = token;
-    let mut completed_trace = true;
-
-    if chain.len() > 1 {
-      for map in &chain[1..] {
-        if let Some(map_token) = lookup_token(
-          map,
-          last_map_token.get_src_line(),
-          last_map_token.get_src_col(),
-        ) {
-          last_map_token = map_token;
+  let traced_tokens = trace_tokens(
+    &tokens,
+    |_| &chain[1..],
+    opts.lookup_bias,
+    false,
+    opts.trace_threads,
+  );
+
+  for traced_line in traced_tokens.chunk_by(|a, b| a.token.get_dst_line() == b.token.get_dst_line())
+  {
+    for (traced, mapping) in traced_line.iter().zip(resolve_traced_line(
+      traced_line,
+      opts.untraceable_token_strategy,
+    )) {
+      let token = traced.token;
+      let (last_map_token, name) = match mapping {
+        TracedMapping::Token(location) => (location, location.get_name().or(token.get_name())),
+        TracedMapping::Location(location) => (location, None),
+        TracedMapping::Unmapped => {
+          builder.add_raw(
+            token.get_dst_line(),
+            token.get_dst_col(),
+            0,
+            0,
+            None,
+            None,
+            false,
+          );
+          continue;
+        }
+        TracedMapping::Dropped => continue,
+      };
+
+      let source = resolve_token_source(&last_map_token.token);
+      let mut srd_id = None;
+
+      if let Some(src) = source {
+        let remapped_src = if let Some(remap_source) = &opts.remap_source {
+          mapped_src_cache
+            .entry(src)
+            .or_insert_with_key(|src| remap_source(src))
+            .to_string()
         } else {
-          completed_trace = false;
-          break;
+          src
+        };
+
+        let src_id = builder.add_source(&remapped_src);
+        extensions.add_source(src_id, &last_map_token.token, &chain);
+        srd_id = Some(src_id);
+      }
+
+      let name_id = name.map(|name| builder.add_name(name));
+
+      let added_token = builder.add_raw(
+        token.get_dst_line(),
+        token.get_dst_col(),
+        last_map_token.get_src_line(),
+        last_map_token.get_src_col(),
+        srd_id,
+        name_id,
+        false,
+      );
+
+      if opts.inline_content && srd_id.is_some() && !builder.has_source_contents(srd_id.unwrap()) {
+        let src_content = read_source_content(
+          last_map_token.token,
+          last_map_token.token.sourcemap(),
+          resolver_or_fs(&opts.source_resolver),
+        );
+
+        if let Some(src_content) = src_content {
+          builder.set_source_contents(added_token.src_id, Some(&src_content));
         }
       }
     }
+  }
 
-    // if we can't trace back to the first map, ignore this token
-    if !completed_trace {
-      // builder.add_token(&token, true);
-      continue;
+  let mut map = GeneratedSourceMap::new(builder.into_sourcemap());
+  extensions.apply(&mut map);
+
+  map
+}
+
+/// A token of the generated code traced back through a source map chain.
+pub(crate) struct TracedToken<'a> {
+  pub token: Token<'a>,
+  /// the location in the first map of the chain, or the last location found if the trace is not completed
+  pub original: TracedLocation<'a>,
+  pub is_completed: bool,
+}
+
+/// A location in the original code of a map: the token of the segment that contains it, plus the column offset into
+/// the segment if the text of the segment is unchanged.
+#[derive(Clone, Copy)]
+pub(crate) struct TracedLocation<'a> {
+  pub token: Token<'a>,
+  pub column_offset: u32,
+}
+
+impl<'a> TracedLocation<'a> {
+  fn new(token: Token<'a>) -> Self {
+    Self {
+      token,
+      column_offset: 0,
     }
+  }
 
-    let source = last_map_token.get_source();
-    let mut srd_id = None;
+  pub fn get_src_line(&self) -> u32 {
+    self.token.get_src_line()
+  }
+
+  pub fn get_src_col(&self) -> u32 {
+    self.token.get_src_col() + self.column_offset
+  }
+
+  pub fn get_name(&self) -> Option<&'a str> {
+    self.token.get_name()
+  }
+}
+
+/// Trace `token` back through `chain`, which is ordered from the map of the generated code to the original one.
+/// `in_chain_file` is whether `token` is known to point at the generated file of the first map of `chain`, like the
+/// tokens of a bundle source traced through its own chain. Otherwise it is checked like between the maps of `chain`,
+/// see [is_traced_into].
+pub(crate) fn trace_token<'a>(
+  token: Token<'a>,
+  chain: &'a [ChainSourceMap],
+  bias: LookupBias,
+  in_chain_file: bool,
+) -> TracedToken<'a> {
+  let mut original = TracedLocation::new(token);
+
+  for (index, map) in chain.iter().enumerate() {
+    if (index > 0 || !in_chain_file) && !is_traced_into(&original.token, map) {
+      break;
+    }
+
+    let (line, col) = (original.get_src_line(), original.get_src_col());
+
+    match map.lookup_token_with_bias(line, col, bias) {
+      Some(map_token) => {
+        original = TracedLocation {
+          token: map_token,
+          column_offset: column_offset_in_segment(original.token, map_token, line, col),
+        }
+      }
+      None => {
+        return TracedToken {
+          token,
+          original,
+          is_completed: false,
+        }
+      }
+    }
+  }
+
+  TracedToken {
+    token,
+    original,
+    is_completed: true,
+  }
+}
+
+/// Tokens traced by a thread at least, fewer tokens are not worth a thread.
+const TRACE_BATCH_SIZE: usize = 4096;
+
+/// Trace `tokens` like [trace_token], `chain_of` gives the chain of each token. The tokens are traced in batches on
+/// up to `threads` threads if there are enough of them, the traced tokens are in the order of `tokens`.
+pub(crate) fn trace_tokens<'a>(
+  tokens: &[Token<'a>],
+  chain_of: impl Fn(&Token<'a>) -> &'a [ChainSourceMap] + Sync,
+  bias: LookupBias,
+  in_chain_file: bool,
+  threads: Option<usize>,
+) -> Vec<TracedToken<'a>> {
+  let trace_batch = |batch: &[Token<'a>]| {
+    batch
+      .iter()
+      .map(|token| trace_token(*token, chain_of(token), bias, in_chain_file))
+      .collect::<Vec<_>>()
+  };
+  let thread_count = threads.unwrap_or_else(|| {
+    std::thread::available_parallelism()
+      .map(|count| count.get())
+      .unwrap_or(1)
+  });
+
+  if thread_count <= 1 || tokens.len() < TRACE_BATCH_SIZE * 2 {
+    return trace_batch(tokens);
+  }
+
+  let batch_size = tokens.len().div_ceil(thread_count).max(TRACE_BATCH_SIZE);
+
+  std::thread::scope(|s| {
+    let handles = tokens
+      .chunks(batch_size)
+      .map(|batch| s.spawn(move || trace_batch(batch)))
+      .collect::<Vec<_>>();
+
+    handles
+      .into_iter()
+      .flat_map(|handle| handle.join().unwrap())
+      .collect()
+  })
+}
+
+/// Whether `token` points at the generated file of `map`, so it is traced further through `map`.
+/// A token of a map with several sources, e.g. of concatenated files, may point at another source, which is then an
+/// original source already. Every token is traced into a map without `file`.
+fn is_traced_into(token: &Token<'_>, map: &SourceMap) -> bool {
+  if token.sourcemap().get_source_count() <= 1 {
+    return true;
+  }
+
+  match (map.get_file(), resolve_token_source(token)) {
+    (Some(file), Some(source)) => source_path::is_same_file(&source, file),
+    _ => true,
+  }
+}
 
-    if let Some(src) = source {
-      let remapped_src = if let Some(remap_source) = &opts.remap_source {
-        mapped_src_cache
-          .entry(src)
-          .or_insert_with(|| remap_source(src))
-          .to_string()
-      } else {
-        src.to_string()
+/// Offset of `(line, col)` from the start of the segment of `map_token` that contains it, like the identity spans of
+/// `@ampproject/remapping`. It is 0 if the segment is named, as the name may be renamed, or if the text of the segment
+/// has changed or can not be compared because the generated code, the source of `generated_token`, or the original
+/// code of `map_token` is not available.
+fn column_offset_in_segment(
+  generated_token: Token<'_>,
+  map_token: Token<'_>,
+  line: u32,
+  col: u32,
+) -> u32 {
+  if map_token.get_dst_line() != line || map_token.get_dst_col() >= col || map_token.has_name() {
+    return 0;
+  }
+
+  let offset = col - map_token.get_dst_col();
+  let is_unchanged = match (
+    generated_token.get_source_view(),
+    map_token.get_source_view(),
+  ) {
+    (Some(generated), Some(original)) => {
+      match (
+        generated.get_line(line),
+        original.get_line(map_token.get_src_line()),
+      ) {
+        (Some(generated_line), Some(original_line)) => {
+          let generated_span = generated_line
+            .chars()
+            .skip(map_token.get_dst_col() as usize)
+            .take(offset as usize);
+          let original_span = original_line
+            .chars()
+            .skip(map_token.get_src_col() as usize)
+            .take(offset as usize);
+
+          generated_span.eq(original_span)
+        }
+        _ => false,
+      }
+    }
+    _ => false,
+  };
+
+  if is_unchanged {
+    offset
+  } else {
+    0
+  }
+}
+
+/// Carries `x_google_ignoreList` and the `x_*` fields of the chain maps that the sources of a collapsed map come from.
+pub(crate) struct ExtensionsCollector<'a> {
+  policy: &'a ExtensionFieldsPolicy,
+  /// the chain map and the source index in it of each source of the collapsed map
+  origins: Vec<Option<(&'a ChainSourceMap, u32)>>,
+}
+
+impl<'a> ExtensionsCollector<'a> {
+  pub fn new(policy: &'a ExtensionFieldsPolicy) -> Self {
+    Self {
+      policy,
+      origins: vec![],
+    }
+  }
+
+  /// Record that the source `src_id` of the collapsed map is the source of `token`, a token of one of the maps of `chain`.
+  pub fn add_source(&mut self, src_id: u32, token: &Token<'a>, chain: &'a [ChainSourceMap]) {
+    let Some(chain_map) = chain
+      .iter()
+      .find(|chain_map| std::ptr::eq(&chain_map.map, token.sourcemap()))
+    else {
+      return;
+    };
+    let index = src_id as usize;
+
+    if self.origins.len() <= index {
+      self.origins.resize(index + 1, None);
+    }
+
+    self.origins[index].get_or_insert((chain_map, token.get_src_id()));
+  }
+
+  pub fn apply(self, map: &mut GeneratedSourceMap) {
+    let source_count = map.get_source_count() as usize;
+    let mut fields: Vec<(String, Value)> = vec![];
+
+    for (src_id, origin) in self.origins.iter().enumerate() {
+      let Some((chain_map, origin_src_id)) = origin else {
+        continue;
+      };
+
+      if chain_map.ignore_list.contains(origin_src_id) {
+        map.add_to_ignore_list(src_id as u32);
+      }
+
+      let per_source_fields = match self.policy {
+        ExtensionFieldsPolicy::Drop => continue,
+        ExtensionFieldsPolicy::Passthrough => &[][..],
+        ExtensionFieldsPolicy::Merge(per_source_fields) => per_source_fields,
       };
 
-      srd_id = Some(builder.add_source(&remapped_src));
+      for (key, value) in &chain_map.extension_fields {
+        let position = fields.iter().position(|(field_key, _)| field_key == key);
+
+        match value {
+          // the indexes of an array with an entry per source only hold in its own map
+          Value::Array(entries) if per_source_fields.contains(key) => {
+            let position = position.unwrap_or_else(|| {
+              fields.push((key.clone(), Value::Array(vec![Value::Null; source_count])));
+              fields.len() - 1
+            });
+
+            if let Value::Array(merged) = &mut fields[position].1 {
+              if merged[src_id].is_null() {
+                merged[src_id] = entries
+                  .get(*origin_src_id as usize)
+                  .cloned()
+                  .unwrap_or_default();
+              }
+            }
+          }
+          _ if position.is_none() => fields.push((key.clone(), value.clone())),
+          _ => {}
+        }
+      }
+    }
+
+    for (key, value) in fields {
+      map.set_extension_field(key, value);
     }
+  }
+}
 
-    let mut name_id = None;
+/// Where a traced token is mapped to.
+pub(crate) enum TracedMapping<'a> {
+  /// the location and its name
+  Token(TracedLocation<'a>),
+  /// only the location
+  Location(TracedLocation<'a>),
+  Unmapped,
+  Dropped,
+}
+
+/// Map the traced tokens of one generated line, the untraceable ones by `strategy`. The tokens of `line` are sorted by
+/// generated column.
+pub(crate) fn resolve_traced_line<'a, 'l>(
+  line: &'l [TracedToken<'a>],
+  strategy: UntraceableTokenStrategy,
+) -> impl Iterator<Item = TracedMapping<'a>> + 'l {
+  let nearest = if strategy == UntraceableTokenStrategy::NearestOnLine {
+    nearest_traced_on_line(line)
+  } else {
+    vec![]
+  };
 
-    if let Some(name) = last_map_token.get_name().or(token.get_name()) {
-      name_id = Some(builder.add_name(name));
+  line.iter().enumerate().map(move |(index, traced)| {
+    if traced.is_completed {
+      return TracedMapping::Token(traced.original);
     }
 
-    let added_token = builder.add_raw(
-      token.get_dst_line(),
-      token.get_dst_col(),
-      last_map_token.get_src_line(),
-      last_map_token.get_src_col(),
-      srd_id,
-      name_id,
-      false,
-    );
+    match strategy {
+      UntraceableTokenStrategy::Drop => TracedMapping::Dropped,
+      UntraceableTokenStrategy::KeepIntermediate => TracedMapping::Token(traced.original),
+      UntraceableTokenStrategy::NearestOnLine => nearest[index]
+        .map(|nearest| TracedMapping::Location(line[nearest].original))
+        .unwrap_or(TracedMapping::Dropped),
+      UntraceableTokenStrategy::Unmapped => TracedMapping::Unmapped,
+    }
+  })
+}
+
+/// Index of the completed token of the same source nearest to each untraceable token of `line` by generated column, the
+/// previous one on a tie. A pass in each direction keeps the last completed token of each source.
+fn nearest_traced_on_line(line: &[TracedToken<'_>]) -> Vec<Option<usize>> {
+  let mut nearest = vec![None; line.len()];
+  let mut last_completed: HashMap<u32, usize> = HashMap::new();
+
+  for (index, traced) in line.iter().enumerate() {
+    let src_id = traced.token.get_src_id();
 
-    if opts.inline_content && srd_id.is_some() && !builder.has_source_contents(srd_id.unwrap()) {
-      let src_content = read_source_content(last_map_token, chain.last().unwrap());
+    if traced.is_completed {
+      last_completed.insert(src_id, index);
+    } else {
+      nearest[index] = last_completed.get(&src_id).copied();
+    }
+  }
+
+  last_completed.clear();
+
+  for (index, traced) in line.iter().enumerate().rev() {
+    let src_id = traced.token.get_src_id();
+
+    if traced.is_completed {
+      last_completed.insert(src_id, index);
+      continue;
+    }
 
-      if let Some(src_content) = src_content {
-        builder.set_source_contents(added_token.src_id, Some(&src_content));
+    if let Some(&next) = last_completed.get(&src_id) {
+      let col = traced.token.get_dst_col();
+      let distance = |index: usize| line[index].token.get_dst_col().abs_diff(col);
+
+      if nearest[index].is_none_or(|previous| distance(next) < distance(previous)) {
+        nearest[index] = Some(next);
       }
     }
   }
 
-  builder.into_sourcemap()
+  nearest
 }
 
 /// if map_token is not exact match, we should use the token next to it to make sure the line mapping is correct.
@@ -140,21 +544,74 @@ pub fn lookup_token<'a>(map: &'a SourceMap, line: u32, col: u32) -> Option<Token
   token
 }
 
-pub fn read_source_content(token: Token<'_>, map: &SourceMap) -> Option<String> {
+/// Source of `token` joined with the `sourceRoot` of its map, see [source_path::join].
+pub fn resolve_token_source(token: &Token<'_>) -> Option<String> {
+  let source = token.get_source()?;
+  let source_root = match token.sourcemap().get_source_root() {
+    Some(source_root) if !source_root.is_empty() => source_root,
+    _ => return Some(source.to_string()),
+  };
+
+  // sourcemap prefixes the sources with the root by a plain string concatenation, undo it to join them properly
+  let raw_source = source
+    .strip_prefix(source_root.strip_suffix('/').unwrap_or(source_root))
+    .and_then(|source| source.strip_prefix('/'))
+    .unwrap_or(source);
+
+  Some(source_path::join(source_root, raw_source))
+}
+
+/// Look up the token of `(line, col)` in `map` by `bias`, [LookupBias::GreatestLowerBound] is [lookup_token].
+pub fn lookup_token_with_bias(
+  map: &SourceMap,
+  line: u32,
+  col: u32,
+  bias: LookupBias,
+) -> Option<Token<'_>> {
+  match bias {
+    LookupBias::GreatestLowerBound => lookup_token(map, line, col),
+    LookupBias::LeastUpperBound => {
+      // the index of the first token at or after (line, col)
+      let (mut low, mut high) = (0, map.get_token_count() as usize);
+
+      while low < high {
+        let mid = low + (high - low) / 2;
+
+        if map.get_token(mid)?.get_dst() < (line, col) {
+          low = mid + 1;
+        } else {
+          high = mid;
+        }
+      }
+
+      map
+        .get_token(low)
+        .filter(|token| token.get_dst_line() == line)
+    }
+    LookupBias::Exact => map
+      .lookup_token(line, col)
+      .filter(|token| token.get_dst() == (line, col)),
+  }
+}
+
+/// Content of the source of `token`, from `sourcesContent` of `map` or supplied by `resolver`.
+pub fn read_source_content(
+  token: Token<'_>,
+  map: &SourceMap,
+  resolver: &dyn SourceResolver,
+) -> Option<String> {
   if let Some(view) = token.get_source_view() {
     Some(view.source().to_string())
-  } else if let Some(src) = token.get_source() {
-    let src = &file_url_to_path(src);
-    // try read source content from disk
+  } else if let Some(src) = resolve_token_source(&token) {
+    let src = &file_url_to_path(&src);
     let map_file = map.get_file();
 
     if PathBuf::from(src).is_absolute() || map_file.is_none() {
-      std::fs::read_to_string(src).ok()
+      resolver.resolve_source(&PathBuf::from(src))
     } else if let Some(map_file) = map_file {
-      let src_file = PathBuf::from(map_file).parent().unwrap().join(src);
-      let src_content = std::fs::read_to_string(src_file).ok();
-
-      src_content
+      let map_dir = Path::new(map_file).parent().unwrap_or(Path::new(""));
+      let src_file = source_path::join(&map_dir.to_string_lossy(), src);
+      resolver.resolve_source(&PathBuf::from(src_file))
     } else {
       None
     }
@@ -176,7 +633,7 @@ impl<'a> CollapsedSourceMap<'a> {
     }
   }
 
-  pub fn tokens(&'a self) -> RefMut<Vec<Token<'a>>> {
+  pub fn tokens(&'a self) -> RefMut<'a, Vec<Token<'a>>> {
     let mut tokens = self.tokens.borrow_mut();
 
     if tokens.is_empty() {
@@ -1,6 +1,18 @@
 #[derive(Debug)]
 pub enum Error {
   IllegalSource,
+  SourceIndexOutOfBounds(usize),
+  /// A group of [crate::bundle::SplitStrategy::Groups] is empty, the value is the index of the group.
+  EmptySplitGroup(usize),
+  /// A source index is given more than once in [crate::bundle::SplitStrategy::Groups].
+  DuplicateSourceIndex(usize),
+  Io(std::io::Error),
+}
+
+impl From<std::io::Error> for Error {
+  fn from(e: std::io::Error) -> Self {
+    Self::Io(e)
+  }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
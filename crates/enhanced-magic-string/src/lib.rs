@@ -2,8 +2,10 @@
 pub mod bundle;
 mod chunk;
 pub mod collapse_sourcemap;
-mod error;
+pub mod error;
 pub mod magic_string;
 mod mappings;
+pub mod source_map;
+pub mod source_resolver;
 pub mod types;
 pub mod utils;
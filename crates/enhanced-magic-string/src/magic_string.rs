@@ -1,17 +1,25 @@
 use std::{
   collections::{HashMap, HashSet},
-  sync::Arc,
+  fmt::Display,
+  io::Write,
+  path::Path,
+  sync::{Arc, OnceLock},
 };
 
-use crate::{error::Result, utils::common::get_relative_path};
+use crate::{error::Result, utils::source_path};
 use parking_lot::Mutex;
-use sourcemap::{SourceMap, SourceMapBuilder};
+use sourcemap::SourceMap;
 
 use crate::{
   chunk::Chunk,
   mappings::Mappings,
+  source_map::{
+    decode_data_url, find_url_comment, ChainSourceMap, DecodedSourceMap, GeneratedCode,
+    GeneratedSourceMap,
+  },
+  source_resolver::{resolver_or_fs, SourceResolver},
   types::SourceMapOptions,
-  utils::{char_string::CharString, get_locator::get_locator},
+  utils::{char_string::CharString, get_locator::get_locator, guess_indent::guess_indent},
 };
 
 pub type ExclusionRange = (usize, usize);
@@ -20,8 +28,18 @@ pub type ExclusionRange = (usize, usize);
 pub struct MagicStringOptions {
   pub filename: Option<String>,
   pub indent_exclusion_ranges: Vec<ExclusionRange>,
+  /// Sources of the generated map that are added to `x_google_ignoreList`, matched with the `sources` written to the
+  /// map by [source_path::is_same_file], e.g. the third-party sources of `source_map_chain`.
   pub ignore_list: Vec<CharString>,
+  /// Source maps of the transforms that produced the original string, in transform order.
+  /// Index maps are flattened, so their sections must have their maps embedded.
   pub source_map_chain: Vec<Arc<String>>,
+  /// Strip the `sourceMappingURL` comment at the end of the original string and push the map it references onto
+  /// `source_map_chain`. Data urls are decoded and other urls are read from disk relative to `filename`.
+  /// The comment is stripped even if the map can not be loaded or is not a valid source map.
+  pub extract_source_map: bool,
+  /// Supplies the external maps for `extract_source_map`, reads from disk if `None`.
+  pub source_resolver: Option<Arc<dyn SourceResolver>>,
 }
 
 pub struct MagicString {
@@ -42,13 +60,33 @@ pub struct MagicString {
   pub indent_str: Option<CharString>,
   pub ignore_list: Vec<CharString>,
   source_map_chain: Vec<Arc<String>>,
-
-  pub separator: char,
+  /// the map of `extract_source_map`, parsed up front so an invalid one is dropped instead of failing later
+  extracted_source_map: Option<ChainSourceMap>,
+  /// `source_map_chain` parsed on first use, shared by every bundle the source is added to
+  parsed_source_map_chain: OnceLock<Arc<Vec<ChainSourceMap>>>,
 }
 
 impl MagicString {
   pub fn new(original: &str, options: Option<MagicStringOptions>) -> Self {
     let options = options.unwrap_or_default();
+    let mut extracted_source_map = None;
+    let original = if options.extract_source_map {
+      match find_url_comment(original) {
+        Some((start, url)) => {
+          extracted_source_map = load_source_map_url(
+            url,
+            options.filename.as_deref(),
+            resolver_or_fs(&options.source_resolver),
+          )
+          .and_then(|map| ChainSourceMap::from_slice(map.as_bytes()).ok());
+
+          &original[..start]
+        }
+        None => original,
+      }
+    } else {
+      original
+    };
     let original = CharString::new(original);
     let chunk = Arc::new(Mutex::new(Chunk::new(0, original.len(), original.clone())));
 
@@ -68,7 +106,8 @@ impl MagicString {
       indent_str: None,
       ignore_list: options.ignore_list,
       source_map_chain: options.source_map_chain,
-      separator: '\n',
+      extracted_source_map,
+      parsed_source_map_chain: OnceLock::new(),
     };
 
     magic_string
@@ -82,74 +121,182 @@ impl MagicString {
   }
 
   pub fn get_source_map_chain(&self) -> Vec<SourceMap> {
-    let mut chain = self
-      .source_map_chain
+    self
+      .parsed_source_map_chain()
       .iter()
-      .map(|source| SourceMap::from_slice(source.as_bytes()).unwrap())
-      .filter(|source| {
-        // if the source map is empty, we should ignore it
-        source.get_token_count() > 0
+      .map(|chain_map| chain_map.map.clone())
+      .collect()
+  }
+
+  /// The maps of `source_map_chain` from the last transform to the first one, the empty maps are ignored.
+  /// They are parsed once and cached.
+  pub(crate) fn parsed_source_map_chain(&self) -> Arc<Vec<ChainSourceMap>> {
+    self
+      .parsed_source_map_chain
+      .get_or_init(|| {
+        let mut chain = self
+          .source_map_chain
+          .iter()
+          .map(|source| ChainSourceMap::from_slice(source.as_bytes()).unwrap())
+          .chain(self.extracted_source_map.clone())
+          .filter(|source| {
+            // if the source map is empty, we should ignore it
+            source.get_token_count() > 0
+          })
+          .collect::<Vec<_>>();
+        chain.reverse();
+
+        Arc::new(chain)
       })
-      .collect::<Vec<_>>();
-    chain.reverse();
+      .clone()
+  }
+
+  /// `[start, end)` ranges of original char indexes within `radius` chars of an edit, i.e. an edited chunk or content
+  /// inserted before or after a chunk.
+  pub(crate) fn edit_ranges(&self, radius: usize) -> Vec<(usize, usize)> {
+    let mut ranges = vec![];
+    let mut push = |start: usize, end: usize| {
+      ranges.push((start.saturating_sub(radius), end.saturating_add(radius)));
+    };
+
+    if !self.intro.is_empty() {
+      push(0, 0);
+    }
 
-    chain
+    self.first_chunk.lock().each_next(|chunk| {
+      if chunk.edited {
+        push(chunk.start, chunk.end);
+      }
+
+      if !chunk.intro.is_empty() {
+        push(chunk.start, chunk.start);
+      }
+
+      if !chunk.outro.is_empty() {
+        push(chunk.end, chunk.end);
+      }
+    });
+
+    if !self.outro.is_empty() {
+      push(self.original.len(), self.original.len());
+    }
+
+    ranges
   }
 
-  pub fn generate_map(&self, opts: SourceMapOptions) -> Result<SourceMap> {
+  /// [SourceMapOptions::url_comment] is ignored, only the map is generated.
+  pub fn generate_map(&self, opts: SourceMapOptions) -> Result<GeneratedSourceMap> {
     let source_index = 0;
     // let names: Vec<&CharString> = self.stored_names.keys().collect();
 
     let locate = get_locator(&self.original);
-    let mut mappings = Mappings::new(opts.hires.unwrap_or_default());
+    let mut mappings = Mappings::new();
+    mappings.set_hires(
+      &opts.hires.unwrap_or_default(),
+      self,
+      self.filename.as_deref(),
+    );
 
     if !self.intro.is_empty() {
-      mappings.advance(&self.intro);
+      mappings.advance(self.intro.as_slice());
     }
 
     self.first_chunk.lock().each_next(|chunk| {
       let loc = locate(chunk.start);
 
       if !chunk.intro.is_empty() {
-        mappings.advance(&chunk.intro);
+        mappings.advance(chunk.intro.as_slice());
       }
 
       if !chunk.edited {
-        mappings.add_unedited_chunk(
+        mappings.add_unedited_range(
           source_index,
-          &chunk,
+          chunk.start,
+          chunk.end,
           &self.original,
           loc,
           &self.sourcemap_locations,
         )
       } else {
-        unimplemented!("chunk.edited")
+        mappings.add_edit(source_index, chunk.content.as_slice(), loc);
       }
 
       if !chunk.outro.is_empty() {
-        mappings.advance(&chunk.outro)
+        mappings.advance(chunk.outro.as_slice())
       }
     });
 
     let source = if let Some(src) = &opts.source {
-      get_relative_path(opts.file.clone().unwrap_or_default().as_str(), src).unwrap()
+      source_path::relative(opts.file.as_deref().unwrap_or_default(), src)
     } else {
       opts.file.clone().unwrap_or_default()
     };
-
-    let mut sourcemap_builder = SourceMapBuilder::new(opts.file.as_ref().map(|f| f.as_str()));
-    let src_id = sourcemap_builder.add_source(&source);
+    let source = if let Some(remap_source) = &opts.remap_source {
+      remap_source(&source)
+    } else {
+      source
+    };
 
     let inline_content = opts.include_content.unwrap_or(false);
 
     let contet = if inline_content {
-      Some(self.original.to_string())
+      Some(self.original.to_string().into())
     } else {
       None
     };
-    sourcemap_builder.set_source_contents(src_id, contet.as_deref());
-    mappings.into_sourcemap_mappings(&mut sourcemap_builder);
-    Ok(sourcemap_builder.into_sourcemap())
+
+    let is_ignored = self
+      .ignore_list
+      .iter()
+      .any(|entry| source_path::is_same_file(&entry.to_string(), &source));
+    let mut map = GeneratedSourceMap::from_mappings(
+      mappings,
+      opts.file.as_deref(),
+      vec![source.into()],
+      vec![contet],
+      vec![],
+    )
+    .with_source_root(opts.source_root.clone());
+
+    if is_ignored {
+      map.add_to_ignore_list(0);
+    }
+
+    Ok(map.with_extension_fields(&opts.extension_fields))
+  }
+
+  /// Generate the code and the source map.
+  /// A `sourceMappingURL` comment is appended to the code if [SourceMapOptions::url_comment] is set.
+  pub fn generate(&self, mut opts: SourceMapOptions) -> Result<GeneratedCode> {
+    let url_comment = opts.url_comment.take();
+    let mut code = self.to_string();
+    let map = self.generate_map(opts)?;
+
+    if let Some(url_comment) = url_comment {
+      code.push('\n');
+      code.push_str(&map.to_comment(&url_comment));
+    }
+
+    Ok(GeneratedCode { code, map })
+  }
+
+  /// Like `generateDecodedMap` of magic-string, the mappings are not encoded.
+  pub fn generate_decoded_map(&self, opts: SourceMapOptions) -> Result<DecodedSourceMap> {
+    Ok(self.generate_map(opts)?.to_decoded())
+  }
+
+  /// The indent string of this source, guessed from the original content if not set. Defaults to a tab.
+  pub fn get_indent_string(&self) -> CharString {
+    self
+      .get_raw_indent_string()
+      .unwrap_or_else(|| CharString::from('\t'))
+  }
+
+  pub(crate) fn get_raw_indent_string(&self) -> Option<CharString> {
+    self
+      .indent_str
+      .clone()
+      .or_else(|| guess_indent(&self.original))
   }
 
   pub fn prepend(&mut self, str: &str) {
@@ -158,25 +305,62 @@ impl MagicString {
     self.intro = new_intro;
   }
 
+  /// Always map the char at `index` of the original string, even in lo-res source maps.
+  pub fn add_sourcemap_location(&mut self, index: usize) {
+    self.sourcemap_locations.insert(index);
+  }
+
   pub fn append(&mut self, str: &str) {
     let mut new_outro = self.outro.clone();
     new_outro.append_str(str);
     self.outro = new_outro;
   }
+
+  /// Write the generated code to `writer` without building it in memory first, e.g. to a file or a hasher.
+  pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> std::io::Result<()> {
+    self.intro.write_to(writer)?;
+    let guard = self.first_chunk.lock();
+    let mut chunk = Some(&*guard);
+
+    while let Some(c) = chunk {
+      c.intro.write_to(writer)?;
+      c.content.write_to(writer)?;
+      c.outro.write_to(writer)?;
+      chunk = c.next();
+    }
+
+    self.outro.write_to(writer)
+  }
+}
+
+/// Load the map referenced by a `sourceMappingURL`, external maps are resolved relative to `filename`.
+fn load_source_map_url(
+  url: &str,
+  filename: Option<&str>,
+  resolver: &dyn SourceResolver,
+) -> Option<String> {
+  if url.starts_with("data:") {
+    return decode_data_url(url);
+  }
+
+  let dir = filename
+    .and_then(|filename| Path::new(filename).parent())
+    .unwrap_or(Path::new(""));
+
+  resolver.resolve_source_map(&dir.join(url))
 }
 
-impl ToString for MagicString {
-  fn to_string(&self) -> String {
-    let mut str = self.intro.to_string();
+impl Display for MagicString {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}", self.intro)?;
     let guard = self.first_chunk.lock();
     let mut chunk = Some(&*guard);
 
     while let Some(c) = chunk {
-      str += &c.to_string();
+      write!(f, "{}", c)?;
       chunk = c.next();
     }
 
-    str += &self.outro.to_string();
-    str
+    write!(f, "{}", self.outro)
   }
 }
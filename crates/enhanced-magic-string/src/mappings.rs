@@ -1,70 +1,176 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, sync::Arc};
 
-use regex::Regex;
-use sourcemap::SourceMapBuilder;
+use sourcemap::{RawToken, SourceMap};
 
 use crate::{
-  chunk::Chunk,
-  types::{MappingsOptionHires, RawSegment, RawSegments},
-  utils::{char_string::CharString, get_locator::Loc},
+  magic_string::MagicString,
+  types::{MappingsOptionHires, Segment, SegmentSource},
+  utils::{char_string::CharString, get_locator::Loc, vlq::encode_mappings},
 };
 
 pub struct Mappings {
-  hires: MappingsOptionHires,
+  resolution: Resolution,
   generated_code_line: usize,
   generated_code_column: usize,
-  raw: Vec<RawSegments>,
+  /// segments of all generated lines, in generated order
+  segments: Vec<Segment>,
+  /// index of the first segment of each generated line in `segments`
+  line_starts: Vec<usize>,
   pending: Option<usize>,
-  word_regex: Regex,
+  /// sorted and merged hi-res ranges of [Resolution::Ranges]
+  hires_ranges: Vec<(usize, usize)>,
+}
+
+/// [MappingsOptionHires] resolved for the source that is being mapped.
+enum Resolution {
+  Hires,
+  Boundary,
+  /// hi-res in `hires_ranges`, lo-res elsewhere
+  Ranges,
 }
 
 impl Mappings {
-  pub fn new(hires: MappingsOptionHires) -> Self {
+  /// Lo-res mappings until [Mappings::set_hires] is called.
+  pub fn new() -> Self {
     Self {
-      hires,
+      resolution: Resolution::Ranges,
       generated_code_line: 0,
       generated_code_column: 0,
-      raw: vec![vec![]],
+      segments: vec![],
+      line_starts: vec![0],
       pending: None,
-      word_regex: Regex::new(r"\w").unwrap(),
+      hires_ranges: vec![],
     }
   }
 
-  pub(crate) fn push_segment(&mut self, segment: RawSegment) {
-    self.raw[self.generated_code_line].push(segment);
+  /// Use `hires` for the mappings of `source` added from now on, `filename` is its key of
+  /// [MappingsOptionHires::SourceRanges].
+  pub(crate) fn set_hires(
+    &mut self,
+    hires: &MappingsOptionHires,
+    source: &MagicString,
+    filename: Option<&str>,
+  ) {
+    self.hires_ranges.clear();
+
+    let mut ranges = match hires {
+      MappingsOptionHires::Bool(true) => {
+        self.resolution = Resolution::Hires;
+        return;
+      }
+      MappingsOptionHires::Boundary => {
+        self.resolution = Resolution::Boundary;
+        return;
+      }
+      MappingsOptionHires::Bool(false) => vec![],
+      MappingsOptionHires::Ranges(ranges) => ranges.clone(),
+      MappingsOptionHires::SourceRanges(ranges) => filename
+        .and_then(|filename| ranges.get(filename))
+        .cloned()
+        .unwrap_or_default(),
+      MappingsOptionHires::AroundEdits(radius) => source.edit_ranges(*radius),
+    };
+    self.resolution = Resolution::Ranges;
+
+    ranges.retain(|(start, end)| start < end);
+    ranges.sort_unstable();
+
+    for (start, end) in ranges {
+      match self.hires_ranges.last_mut() {
+        Some((_, last_end)) if start <= *last_end => *last_end = (*last_end).max(end),
+        _ => self.hires_ranges.push((start, end)),
+      }
+    }
+  }
+
+  fn is_hires_at(&self, original_char_index: usize) -> bool {
+    match self.resolution {
+      Resolution::Hires | Resolution::Boundary => true,
+      Resolution::Ranges => {
+        let index = self
+          .hires_ranges
+          .partition_point(|(_, end)| *end <= original_char_index);
+
+        self
+          .hires_ranges
+          .get(index)
+          .is_some_and(|(start, _)| *start <= original_char_index)
+      }
+    }
+  }
+
+  pub(crate) fn push_segment(&mut self, segment: Segment) {
+    self.segments.push(segment);
   }
 
   pub(crate) fn inc_generated_code_line(&mut self) {
     self.generated_code_line += 1;
     self.generated_code_column = 0;
-    self.raw.push(vec![]);
+    self.line_starts.push(self.segments.len());
+  }
+
+  /// Replace the source index of every segment with `map(source_index)`.
+  pub(crate) fn map_source_indexes(&mut self, map: impl Fn(u32) -> u32) {
+    for source in self
+      .segments
+      .iter_mut()
+      .filter_map(|segment| segment.source.as_mut())
+    {
+      source.source_index = map(source.source_index);
+    }
+  }
+
+  /// Number of generated lines.
+  pub fn line_count(&self) -> usize {
+    self.line_starts.len()
   }
 
-  pub fn add_unedited_chunk(
+  /// Segments of each generated line.
+  pub fn lines(&self) -> impl Iterator<Item = &[Segment]> {
+    self.line_starts.iter().enumerate().map(|(line, start)| {
+      let end = self
+        .line_starts
+        .get(line + 1)
+        .copied()
+        .unwrap_or(self.segments.len());
+
+      &self.segments[*start..end]
+    })
+  }
+
+  /// Add the mappings of `original[start..end]`, which is emitted unchanged. `loc` is the location of `start`.
+  pub fn add_unedited_range(
     &mut self,
-    source_index: isize,
-    chunk: &Chunk,
+    source_index: usize,
+    start: usize,
+    end: usize,
     original: &CharString,
     mut loc: Loc,
     sourcemap_locations: &HashSet<usize>,
   ) {
-    let mut original_char_index = chunk.start;
+    let mut original_char_index = start;
     let mut first = true;
     let mut char_in_hires_boundary = false;
 
-    while original_char_index < chunk.end {
+    while original_char_index < end {
       let char = original.get(original_char_index).unwrap();
 
-      if self.hires.is_truthy() || first || sourcemap_locations.contains(&original_char_index) {
-        let segment = vec![
-          self.generated_code_column,
-          source_index as usize,
-          loc.line,
-          loc.column,
-        ];
+      if self.is_hires_at(original_char_index)
+        || first
+        || sourcemap_locations.contains(&original_char_index)
+      {
+        let segment = Segment {
+          generated_column: self.generated_code_column as u32,
+          source: Some(SegmentSource {
+            source_index: source_index as u32,
+            original_line: loc.line as u32,
+            original_column: loc.column as u32,
+            name_index: None,
+          }),
+        };
 
-        if self.hires.is_boundary() {
-          if self.word_regex.is_match(char.to_string().as_str()) {
+        if matches!(self.resolution, Resolution::Boundary) {
+          if is_word_char(*char) {
             if !char_in_hires_boundary {
               self.push_segment(segment);
               char_in_hires_boundary = true;
@@ -95,46 +201,101 @@ impl Mappings {
     self.pending = None;
   }
 
-  pub fn advance(&mut self, str: &CharString) {
-    if str.is_empty() {
-      return;
+  /// Add the mappings of `content`, which replaces the original text at `loc`. Like magic-string, the start of each
+  /// line of `content` is mapped to `loc`.
+  pub fn add_edit(&mut self, source_index: usize, content: &[char], loc: Loc) {
+    if !content.is_empty() {
+      let mut line_start = 0;
+
+      for (index, char) in content.iter().enumerate() {
+        // a line break that ends the content is left to `advance`, the code after it starts the next line
+        if *char == '\n' && index + 1 < content.len() {
+          self.push_edit_segment(source_index, &loc);
+          self.inc_generated_code_line();
+          line_start = index + 1;
+        }
+      }
+
+      self.push_edit_segment(source_index, &loc);
+      self.advance(&content[line_start..]);
     }
 
-    let lines = str.split('\n');
+    self.pending = None;
+  }
+
+  fn push_edit_segment(&mut self, source_index: usize, loc: &Loc) {
+    self.push_segment(Segment {
+      generated_column: self.generated_code_column as u32,
+      source: Some(SegmentSource {
+        source_index: source_index as u32,
+        original_line: loc.line as u32,
+        original_column: loc.column as u32,
+        name_index: None,
+      }),
+    });
+  }
+
+  pub fn advance(&mut self, str: &[char]) {
+    let mut last_line_start = 0;
 
-    if lines.len() > 1 {
-      for _ in 1..lines.len() {
+    for (index, char) in str.iter().enumerate() {
+      if *char == '\n' {
         self.inc_generated_code_line();
+        last_line_start = index + 1;
       }
     }
 
-    self.generated_code_column += lines.last().unwrap().len();
+    self.generated_code_column += str.len() - last_line_start;
   }
 
-  pub(crate) fn into_sourcemap_mappings(self, builder: &mut SourceMapBuilder) {
-    let mut generated_code_line = 0u32;
-
-    for segments in self.raw {
-      for segment in segments {
-        let dst_line = generated_code_line;
-        let dst_col = segment[0];
-        let src_line = segment[2];
-        let src_col = segment[3];
-        let src_id = segment[1];
-        let name_id = segment.get(4).map(|id| *id as u32);
-
-        builder.add_raw(
-          dst_line as u32,
-          dst_col as u32,
-          src_line as u32,
-          src_col as u32,
-          Some(src_id as u32),
-          name_id,
-          false,
-        );
-      }
+  /// Encode the `mappings` field directly from the segments, see [encode_mappings].
+  pub fn encode(&self) -> String {
+    encode_mappings(self.lines())
+  }
+
+  /// Build a [SourceMap] from the segments directly, without the re-sorting and re-encoding of a [sourcemap::SourceMapBuilder].
+  pub(crate) fn into_sourcemap(
+    self,
+    file: Option<&str>,
+    sources: Vec<Arc<str>>,
+    sources_content: Vec<Option<Arc<str>>>,
+    names: Vec<Arc<str>>,
+  ) -> SourceMap {
+    let mut tokens = Vec::with_capacity(self.segments.len());
+
+    for (generated_line, segments) in self.lines().enumerate() {
+      tokens.extend(segments.iter().map(|segment| {
+        let source = segment.source.as_ref();
 
-      generated_code_line += 1;
+        RawToken {
+          dst_line: generated_line as u32,
+          dst_col: segment.generated_column,
+          src_line: source.map_or(!0, |source| source.original_line),
+          src_col: source.map_or(!0, |source| source.original_column),
+          src_id: source.map_or(!0, |source| source.source_index),
+          name_id: source.and_then(|source| source.name_index).unwrap_or(!0),
+          is_range: false,
+        }
+      }));
     }
+
+    let sources_content = if sources_content.iter().any(|content| content.is_some()) {
+      Some(sources_content)
+    } else {
+      None
+    };
+
+    SourceMap::new(
+      file.map(Into::into),
+      tokens,
+      names,
+      sources,
+      sources_content,
+    )
   }
 }
+
+/// Same as the unicode aware `\w` of a regex.
+fn is_word_char(char: char) -> bool {
+  regex_syntax::is_word_character(char)
+}
@@ -0,0 +1,570 @@
+use std::{
+  borrow::Cow,
+  io::Write,
+  ops::Deref,
+  sync::{Arc, OnceLock},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+use sourcemap::{DecodedMap, SourceMap, Token};
+
+use crate::{
+  collapse_sourcemap::LineIndex,
+  mappings::Mappings,
+  types::{CommentStyle, LookupBias, Segment, SegmentSource, SourceMapComment, SourceMapUrl},
+  utils::vlq::MappingsEncoder,
+};
+
+/// Prefix of the data url of an inlined source map.
+pub const DATA_URL_PREFIX: &str = "data:application/json;charset=utf-8;base64,";
+
+/// Code and source map generated together, e.g. by [crate::bundle::Bundle::generate].
+pub struct GeneratedCode {
+  pub code: String,
+  pub map: GeneratedSourceMap,
+}
+
+/// A generated source map, with the fields that [SourceMap] can not hold yet like `x_google_ignoreList`.
+/// It derefs to [SourceMap], so all the methods of [SourceMap] are available.
+pub struct GeneratedSourceMap {
+  map: SourceMap,
+  ignore_list: Vec<u32>,
+  /// `mappings` encoded directly from the segments, for the maps that are not traced through a source map chain
+  encoded_mappings: Option<String>,
+  /// number of generated lines, including the trailing lines without tokens
+  line_count: usize,
+  /// kept out of `map`, which would prefix its sources with it
+  source_root: Option<String>,
+  /// `x_*` fields other than `x_google_ignoreList`
+  extension_fields: Vec<(String, Value)>,
+}
+
+impl GeneratedSourceMap {
+  pub fn new(mut map: SourceMap) -> Self {
+    let source_root = map.get_source_root().map(ToString::to_string);
+    map.set_source_root(None::<&str>);
+    let line_count = map
+      .tokens()
+      .last()
+      .map(|token| token.get_dst_line() as usize + 1)
+      .unwrap_or(0);
+
+    Self {
+      map,
+      ignore_list: vec![],
+      encoded_mappings: None,
+      line_count,
+      source_root,
+      extension_fields: vec![],
+    }
+  }
+
+  pub(crate) fn from_mappings(
+    mappings: Mappings,
+    file: Option<&str>,
+    sources: Vec<Arc<str>>,
+    sources_content: Vec<Option<Arc<str>>>,
+    names: Vec<Arc<str>>,
+  ) -> Self {
+    let encoded_mappings = mappings.encode();
+    let line_count = mappings.line_count();
+
+    Self {
+      map: mappings.into_sourcemap(file, sources, sources_content, names),
+      ignore_list: vec![],
+      encoded_mappings: Some(encoded_mappings),
+      line_count,
+      source_root: None,
+      extension_fields: vec![],
+    }
+  }
+
+  /// Set the number of generated lines, so the trailing lines without tokens are kept in the mappings.
+  pub(crate) fn with_line_count(mut self, line_count: usize) -> Self {
+    self.line_count = self.line_count.max(line_count);
+    self
+  }
+
+  pub(crate) fn with_source_root(mut self, source_root: Option<String>) -> Self {
+    self.source_root = source_root;
+    self
+  }
+
+  /// The `sourceRoot` field. The sources of the inner [SourceMap] are not prefixed with it.
+  pub fn get_source_root(&self) -> Option<&str> {
+    self.source_root.as_deref()
+  }
+
+  /// Indexes of the sources in `x_google_ignoreList`.
+  pub fn ignore_list(&self) -> &[u32] {
+    &self.ignore_list
+  }
+
+  pub fn add_to_ignore_list(&mut self, src_id: u32) {
+    if !self.ignore_list.contains(&src_id) {
+      self.ignore_list.push(src_id);
+    }
+  }
+
+  /// The `x_*` fields other than `x_google_ignoreList`, in the order they are written.
+  pub fn extension_fields(&self) -> &[(String, Value)] {
+    &self.extension_fields
+  }
+
+  /// Set an `x_*` field, replacing the one with the same key.
+  pub fn set_extension_field(&mut self, key: impl Into<String>, value: Value) {
+    let key = key.into();
+
+    match self
+      .extension_fields
+      .iter_mut()
+      .find(|(field_key, _)| *field_key == key)
+    {
+      Some((_, field_value)) => *field_value = value,
+      None => self.extension_fields.push((key, value)),
+    }
+  }
+
+  /// Set the custom fields of [crate::types::SourceMapOptions::extension_fields].
+  pub(crate) fn with_extension_fields(mut self, fields: &[(String, Value)]) -> Self {
+    for (key, value) in fields {
+      self.set_extension_field(key.clone(), value.clone());
+    }
+    self
+  }
+
+  pub fn into_inner(mut self) -> SourceMap {
+    self.map.set_source_root(self.source_root);
+    self.map
+  }
+
+  /// Segments of each generated line, every generated line is kept like magic-string does.
+  pub fn decoded_mappings(&self) -> Vec<Vec<Segment>> {
+    let mut lines: Vec<Vec<Segment>> = vec![vec![]; self.line_count];
+
+    for token in self.map.tokens() {
+      let line = token.get_dst_line() as usize;
+
+      if lines.len() <= line {
+        lines.resize(line + 1, vec![]);
+      }
+
+      lines[line].push(segment_of_token(&token));
+    }
+
+    lines
+  }
+
+  /// The VLQ encoded `mappings` field, see [GeneratedSourceMap::decoded_mappings].
+  /// It is encoded while the map is generated if no source map chain is traced.
+  pub fn encoded_mappings(&self) -> Cow<'_, str> {
+    if let Some(encoded_mappings) = &self.encoded_mappings {
+      return Cow::Borrowed(encoded_mappings);
+    }
+
+    let mut encoder = MappingsEncoder::default();
+    let mut line = 0;
+
+    for token in self.map.tokens() {
+      while line < token.get_dst_line() as usize {
+        encoder.next_line();
+        line += 1;
+      }
+
+      encoder.push(&segment_of_token(&token));
+    }
+
+    while line + 1 < self.line_count {
+      encoder.next_line();
+      line += 1;
+    }
+
+    Cow::Owned(encoder.finish())
+  }
+
+  /// The map with unencoded mappings, like `generateDecodedMap` of magic-string.
+  pub fn to_decoded(&self) -> DecodedSourceMap {
+    DecodedSourceMap {
+      file: self.map.get_file().map(ToString::to_string),
+      source_root: self.source_root.clone(),
+      sources: self.map.sources().map(ToString::to_string).collect(),
+      sources_content: self
+        .map
+        .source_contents()
+        .map(|content| content.map(ToString::to_string))
+        .collect(),
+      names: self.map.names().map(ToString::to_string).collect(),
+      mappings: self.decoded_mappings(),
+      ignore_list: self.ignore_list.clone(),
+    }
+  }
+
+  /// The source map as a base64 data url, like `toUrl` of magic-string.
+  pub fn to_url(&self) -> String {
+    let json = self.to_json(&SourceMapJsonOptions::default());
+
+    format!("{DATA_URL_PREFIX}{}", STANDARD.encode(json))
+  }
+
+  /// A `sourceMappingURL` comment that references this map, without a leading newline.
+  pub fn to_comment(&self, comment: &SourceMapComment) -> String {
+    let url = match &comment.url {
+      SourceMapUrl::Inline => Cow::Owned(self.to_url()),
+      SourceMapUrl::External(url) => Cow::Borrowed(url.as_str()),
+    };
+
+    match comment.style {
+      CommentStyle::Js => format!("//# sourceMappingURL={url}"),
+      CommentStyle::Css => format!("/*# sourceMappingURL={url} */"),
+    }
+  }
+
+  /// Serialize the source map as compact json, including `x_google_ignoreList`.
+  pub fn to_writer<W: Write>(&self, w: W) -> std::io::Result<()> {
+    self.to_writer_with_options(w, &SourceMapJsonOptions::default())
+  }
+
+  pub fn to_writer_with_options<W: Write>(
+    &self,
+    mut w: W,
+    opts: &SourceMapJsonOptions,
+  ) -> std::io::Result<()> {
+    w.write_all(self.to_json(opts).as_bytes())
+  }
+
+  /// Serialize the source map as json. Keys are in the order of magic-string unless [SourceMapJsonOptions::sort_keys] is set.
+  pub fn to_json(&self, opts: &SourceMapJsonOptions) -> String {
+    let mut fields: Vec<(&str, Value)> = vec![("version", Value::from(3))];
+
+    if let Some(file) = self.map.get_file() {
+      fields.push(("file", Value::from(file)));
+    }
+
+    if let Some(source_root) = &self.source_root {
+      fields.push(("sourceRoot", Value::from(source_root.as_str())));
+    }
+
+    fields.push(("sources", self.map.sources().collect()));
+
+    if self.map.source_contents().any(|content| content.is_some()) {
+      fields.push((
+        "sourcesContent",
+        self
+          .map
+          .source_contents()
+          .map(|content| content.map(Value::from).unwrap_or(Value::Null))
+          .collect(),
+      ));
+    }
+
+    if !opts.omit_empty_names || self.map.get_name_count() > 0 {
+      fields.push(("names", self.map.names().collect()));
+    }
+
+    fields.push(("mappings", Value::from(self.encoded_mappings())));
+
+    if !self.ignore_list.is_empty() {
+      fields.push(("x_google_ignoreList", Value::from(self.ignore_list.clone())));
+    }
+
+    for (key, value) in &self.extension_fields {
+      fields.push((key, value.clone()));
+    }
+
+    if opts.sort_keys {
+      fields.sort_by_key(|(key, _)| *key);
+    }
+
+    let fields = OrderedFields(&fields);
+    let json = if opts.pretty {
+      serde_json::to_string_pretty(&fields)
+    } else {
+      serde_json::to_string(&fields)
+    };
+
+    json.expect("a source map is always serializable")
+  }
+}
+
+/// Fields of a json object serialized in their order, [serde_json::Map] sorts its keys.
+struct OrderedFields<'a>(&'a [(&'a str, Value)]);
+
+impl Serialize for OrderedFields<'_> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(self.0.iter().map(|(key, value)| (key, value)))
+  }
+}
+
+fn segment_of_token(token: &Token<'_>) -> Segment {
+  Segment {
+    generated_column: token.get_dst_col(),
+    source: token.has_source().then(|| SegmentSource {
+      source_index: token.get_src_id(),
+      original_line: token.get_src_line(),
+      original_column: token.get_src_col(),
+      name_index: token.has_name().then(|| token.get_name_id()),
+    }),
+  }
+}
+
+/// Find the `sourceMappingURL` comment at the end of `code`, in js (`//# ...`) or css (`/*# ... */`) syntax.
+/// Only whitespace may follow the comment. Returns the byte offset where the comment starts and the url.
+pub fn find_url_comment(code: &str) -> Option<(usize, &str)> {
+  let trimmed = code.trim_end();
+
+  if let Some(body) = trimmed.strip_suffix("*/") {
+    let start = body.rfind("/*")?;
+    let url = url_of_comment_body(&body[start + 2..])?;
+
+    return Some((start, url));
+  }
+
+  let line_start = trimmed.rfind('\n').map(|index| index + 1).unwrap_or(0);
+
+  trimmed[line_start..]
+    .match_indices("//")
+    .find_map(|(index, _)| {
+      let start = line_start + index;
+      url_of_comment_body(&trimmed[start + 2..]).map(|url| (start, url))
+    })
+}
+
+/// `# sourceMappingURL=url` or the legacy `@ sourceMappingURL=url`.
+fn url_of_comment_body(body: &str) -> Option<&str> {
+  let url = body
+    .strip_prefix('#')
+    .or_else(|| body.strip_prefix('@'))?
+    .trim_start()
+    .strip_prefix("sourceMappingURL=")?
+    .trim();
+
+  (!url.is_empty() && !url.contains(char::is_whitespace)).then_some(url)
+}
+
+/// Parse a source map. An index map is flattened to a regular one, its sections must have their maps embedded.
+pub fn parse_source_map(json: &[u8]) -> sourcemap::Result<SourceMap> {
+  match sourcemap::decode_slice(json)? {
+    DecodedMap::Regular(map) => Ok(map),
+    DecodedMap::Index(index) => index.flatten(),
+    DecodedMap::Hermes(hermes) => Ok(SourceMap::clone(&hermes)),
+  }
+}
+
+/// A map of a source map chain, with the fields that [SourceMap] does not keep.
+#[derive(Debug, Clone)]
+pub struct ChainSourceMap {
+  pub map: SourceMap,
+  /// `x_google_ignoreList` and `ignoreList`
+  pub ignore_list: Vec<u32>,
+  /// the `x_*` fields other than `x_google_ignoreList`
+  pub extension_fields: Vec<(String, Value)>,
+  /// built on the first lookup, `map` should not be modified after that
+  line_index: OnceLock<LineIndex>,
+}
+
+impl ChainSourceMap {
+  /// Parse a source map like [parse_source_map], together with its top level `x_*` fields.
+  pub fn from_slice(json: &[u8]) -> sourcemap::Result<Self> {
+    let map = parse_source_map(json)?;
+    let mut ignore_list = vec![];
+    let mut extension_fields = vec![];
+
+    // sourcemap also accepts a junk header before the json, the fields are not read then
+    if let Ok(fields) = serde_json::from_slice::<serde_json::Map<String, Value>>(json) {
+      for (key, value) in fields {
+        if key == "x_google_ignoreList" || key == "ignoreList" {
+          for src_id in serde_json::from_value::<Vec<u32>>(value).unwrap_or_default() {
+            if !ignore_list.contains(&src_id) {
+              ignore_list.push(src_id);
+            }
+          }
+        } else if key.starts_with("x_") {
+          extension_fields.push((key, value));
+        }
+      }
+    }
+
+    Ok(Self {
+      map,
+      ignore_list,
+      extension_fields,
+      line_index: OnceLock::new(),
+    })
+  }
+
+  /// Same as [crate::collapse_sourcemap::lookup_token_with_bias], but only searches the tokens of `line`.
+  pub fn lookup_token_with_bias(&self, line: u32, col: u32, bias: LookupBias) -> Option<Token<'_>> {
+    self
+      .line_index
+      .get_or_init(|| LineIndex::new(&self.map))
+      .lookup(&self.map, line, col, bias)
+  }
+}
+
+impl From<SourceMap> for ChainSourceMap {
+  fn from(map: SourceMap) -> Self {
+    Self {
+      map,
+      ignore_list: vec![],
+      extension_fields: vec![],
+      line_index: OnceLock::new(),
+    }
+  }
+}
+
+impl Deref for ChainSourceMap {
+  type Target = SourceMap;
+
+  fn deref(&self) -> &Self::Target {
+    &self.map
+  }
+}
+
+/// Decode a json data url, e.g. the one of [GeneratedSourceMap::to_url]. `None` if `url` is not a valid json data url.
+pub fn decode_data_url(url: &str) -> Option<String> {
+  let (header, data) = url.strip_prefix("data:")?.split_once(',')?;
+  let mut params = header.split(';');
+
+  if params.next() != Some("application/json") {
+    return None;
+  }
+
+  let bytes = if params.any(|param| param == "base64") {
+    STANDARD.decode(data).ok()?
+  } else {
+    percent_decode(data)?
+  };
+
+  String::from_utf8(bytes).ok()
+}
+
+/// Decode the `%XX` escapes of a data url, `None` if an escape is malformed.
+fn percent_decode(data: &str) -> Option<Vec<u8>> {
+  let mut bytes = Vec::with_capacity(data.len());
+  let mut rest = data.as_bytes();
+
+  while let Some((&byte, tail)) = rest.split_first() {
+    if byte == b'%' {
+      let digit = |index: usize| (*tail.get(index)? as char).to_digit(16);
+      bytes.push((digit(0)? * 16 + digit(1)?) as u8);
+      rest = &tail[2..];
+    } else {
+      bytes.push(byte);
+      rest = tail;
+    }
+  }
+
+  Some(bytes)
+}
+
+impl Deref for GeneratedSourceMap {
+  type Target = SourceMap;
+
+  fn deref(&self) -> &Self::Target {
+    &self.map
+  }
+}
+
+impl From<SourceMap> for GeneratedSourceMap {
+  fn from(map: SourceMap) -> Self {
+    Self::new(map)
+  }
+}
+
+/// A source map with unencoded mappings, see [GeneratedSourceMap::to_decoded].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DecodedSourceMap {
+  pub file: Option<String>,
+  pub source_root: Option<String>,
+  pub sources: Vec<String>,
+  pub sources_content: Vec<Option<String>>,
+  pub names: Vec<String>,
+  /// segments of each generated line
+  pub mappings: Vec<Vec<Segment>>,
+  /// `x_google_ignoreList`
+  pub ignore_list: Vec<u32>,
+}
+
+/// Options of the json serialization of a [GeneratedSourceMap].
+#[derive(Debug, Default, Clone)]
+pub struct SourceMapJsonOptions {
+  /// Indent with 2 spaces like `JSON.stringify(map, null, 2)`.
+  pub pretty: bool,
+  /// Sort the keys alphabetically instead of the magic-string order.
+  pub sort_keys: bool,
+  /// Omit `names` if it is empty.
+  pub omit_empty_names: bool,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_find_url_comment() {
+    assert_eq!(
+      find_url_comment("a();\n//# sourceMappingURL=a.js.map\n"),
+      Some((5, "a.js.map"))
+    );
+    assert_eq!(
+      find_url_comment("a {}\n/*# sourceMappingURL=a.css.map */"),
+      Some((5, "a.css.map"))
+    );
+    assert_eq!(
+      find_url_comment("//@ sourceMappingURL=a.js.map"),
+      Some((0, "a.js.map"))
+    );
+    assert_eq!(
+      find_url_comment("//# sourceMappingURL=a.js.map\na();"),
+      None
+    );
+    assert_eq!(
+      find_url_comment("a('http://a'); //# sourceMappingURL=a.js.map"),
+      Some((15, "a.js.map"))
+    );
+    assert_eq!(find_url_comment("// a comment"), None);
+  }
+
+  #[test]
+  fn test_decode_data_url() {
+    assert_eq!(
+      decode_data_url("data:application/json;charset=utf-8;base64,e30="),
+      Some("{}".to_string())
+    );
+    assert_eq!(
+      decode_data_url("data:application/json,{}"),
+      Some("{}".to_string())
+    );
+    assert_eq!(
+      decode_data_url("data:application/json,%7B%22version%22%3A3%7D"),
+      Some(r#"{"version":3}"#.to_string())
+    );
+    assert_eq!(decode_data_url("data:application/json,%7"), None);
+    assert_eq!(decode_data_url("data:application/json,%zz"), None);
+    assert_eq!(decode_data_url("data:text/plain;base64,e30="), None);
+    assert_eq!(decode_data_url("a.js.map"), None);
+  }
+
+  #[test]
+  fn test_to_json() {
+    let mut builder = sourcemap::SourceMapBuilder::new(None);
+    let src_id = builder.add_source("a\"b\\c\nd\u{1}€.js");
+    builder.add_raw(0, 0, 0, 0, Some(src_id), None, false);
+    let map = GeneratedSourceMap::new(builder.into_sourcemap());
+
+    assert_eq!(
+      map.to_json(&SourceMapJsonOptions::default()),
+      r#"{"version":3,"sources":["a\"b\\c\nd\u0001€.js"],"names":[],"mappings":"AAAA"}"#
+    );
+    assert_eq!(
+      map.to_json(&SourceMapJsonOptions {
+        pretty: true,
+        sort_keys: true,
+        omit_empty_names: true,
+      }),
+      "{\n  \"mappings\": \"AAAA\",\n  \"sources\": [\n    \"a\\\"b\\\\c\\nd\\u0001€.js\"\n  ],\n  \"version\": 3\n}"
+    );
+  }
+}
@@ -0,0 +1,40 @@
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+/// Supplies the content of the files that a source map refers to, e.g. the original sources that are inlined into
+/// `sourcesContent` and the external maps referenced by a `sourceMappingURL` comment.
+/// Implement it to read from an in-memory filesystem, virtual modules or a sandbox instead of the disk.
+pub trait SourceResolver: Send + Sync {
+  /// Content of the source file at `path`, `None` if it does not exist.
+  fn resolve_source(&self, path: &Path) -> Option<String>;
+
+  /// Content of the external source map at `path`, defaults to [SourceResolver::resolve_source].
+  fn resolve_source_map(&self, path: &Path) -> Option<String> {
+    self.resolve_source(path)
+  }
+}
+
+/// Reads the files from disk, used when no resolver is set.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FsSourceResolver;
+
+impl SourceResolver for FsSourceResolver {
+  fn resolve_source(&self, path: &Path) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+  }
+}
+
+/// An in-memory filesystem keyed by path.
+impl SourceResolver for HashMap<PathBuf, String> {
+  fn resolve_source(&self, path: &Path) -> Option<String> {
+    self.get(path).cloned()
+  }
+}
+
+/// The resolver to use when `resolver` is not set.
+pub(crate) fn resolver_or_fs(resolver: &Option<Arc<dyn SourceResolver>>) -> &dyn SourceResolver {
+  resolver.as_deref().unwrap_or(&FsSourceResolver)
+}
@@ -1,3 +1,7 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::source_resolver::SourceResolver;
+
 /// Whether the mapping should be high-resolution.
 /// Hi-res mappings map every single character, meaning (for example) your devtools will always
 /// be able to pinpoint the exact location of function calls and so on.
@@ -6,9 +10,24 @@
 /// You can also set `"boundary"` to generate a semi-hi-res mappings segmented per word boundary
 /// instead of per character, suitable for string semantics that are separated by words.
 /// If sourcemap locations have been specified with s.addSourceMapLocation(), they will be used here.
+/// `Ranges` generates hi-res mappings only for the chars in the given ranges of original char indexes, e.g. around the
+/// code changed by a plugin, and lo-res mappings elsewhere. `SourceRanges` does the same with the ranges of each source of
+/// a bundle, and `AroundEdits` with the ranges around the edits of the string.
+#[derive(Clone)]
 pub enum MappingsOptionHires {
   Bool(bool),
   Boundary,
+  /// `[start, end)` ranges of original char indexes, they may overlap and do not need to be sorted.
+  /// In a bundle they apply to the original char indexes of every source.
+  Ranges(Vec<(usize, usize)>),
+  /// Like `Ranges`, keyed by the filename a source was added to a bundle with, or the filename of a [MagicString].
+  /// Sources without ranges get lo-res mappings.
+  ///
+  /// [MagicString]: crate::magic_string::MagicString
+  SourceRanges(HashMap<String, Vec<(usize, usize)>>),
+  /// Hi-res mappings for the original chars within the given number of chars of an edit, i.e. an overwritten or
+  /// removed range or content inserted at an original char index.
+  AroundEdits(usize),
 }
 
 impl Default for MappingsOptionHires {
@@ -19,22 +38,108 @@ impl Default for MappingsOptionHires {
 
 impl MappingsOptionHires {
   pub fn is_boundary(&self) -> bool {
-    match self {
-      Self::Bool(_) => false,
-      Self::Boundary => true,
-    }
+    matches!(self, Self::Boundary)
   }
 
+  /// Whether any char may get a hi-res mapping.
   pub fn is_truthy(&self) -> bool {
     match self {
       Self::Bool(b) => *b,
       Self::Boundary => true,
+      Self::Ranges(ranges) => ranges.iter().any(|(start, end)| start < end),
+      Self::SourceRanges(ranges) => ranges.values().flatten().any(|(start, end)| start < end),
+      Self::AroundEdits(radius) => *radius > 0,
     }
   }
 }
 
-pub type RawSegment = Vec<usize>;
-pub type RawSegments = Vec<RawSegment>;
+/// Maps a source filename to the one written to the generated source map.
+pub type RemapSource = Box<dyn Fn(&str) -> String>;
+
+/// What to do with a token of the generated code that can not be traced back through every map of a source map chain.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum UntraceableTokenStrategy {
+  /// Drop the token, the code after it stays mapped by the previous token of the line.
+  #[default]
+  Drop,
+  /// Keep the token mapped to the source of the last map it could be traced through.
+  KeepIntermediate,
+  /// Map the token to the original location of the nearest traced token of the same generated line and source.
+  /// The token is dropped if there is none.
+  NearestOnLine,
+  /// Emit a segment without a source, which marks the code after it as unmapped.
+  Unmapped,
+}
+
+/// How a location is looked up in the segments of a source map when a source map chain is traced.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LookupBias {
+  /// The segment at or before the location. If the location is before the first segment of its line, the segment
+  /// that starts the line is used instead of the last one of the previous line.
+  #[default]
+  GreatestLowerBound,
+  /// The segment at or after the location on the same line.
+  LeastUpperBound,
+  /// Only a segment that starts exactly at the location.
+  Exact,
+}
+
+/// What happens to the `x_*` extension fields of the maps that the sources are traced back to, like
+/// `x_facebook_sources` or `x_metro_module_paths`. `x_google_ignoreList` and `ignoreList` are always carried forward.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum ExtensionFieldsPolicy {
+  /// Drop the fields.
+  #[default]
+  Drop,
+  /// Copy the fields unchanged, the first map with a field wins.
+  Passthrough,
+  /// Like [ExtensionFieldsPolicy::Passthrough], but the named fields are arrays with an entry per source, which are
+  /// remapped to the sources of the generated map and merged across the maps. A source without an entry gets `null`.
+  Merge(Vec<String>),
+}
+
+/// A mapping from a column of a generated line to a location of an original source, all 0-based.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+  pub generated_column: u32,
+  /// `None` for a segment with only the generated column, which marks the code after it as unmapped.
+  pub source: Option<SegmentSource>,
+}
+
+/// The original location of a [Segment].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentSource {
+  pub source_index: u32,
+  pub original_line: u32,
+  pub original_column: u32,
+  pub name_index: Option<u32>,
+}
+
+/// Where the source map referenced by a `sourceMappingURL` comment is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceMapUrl {
+  /// The map is inlined as a base64 data url.
+  Inline,
+  /// The url of the map file, usually relative to the generated file.
+  External(String),
+}
+
+/// Comment syntax of the generated code.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CommentStyle {
+  /// `//# sourceMappingURL=...`
+  #[default]
+  Js,
+  /// `/*# sourceMappingURL=... */`
+  Css,
+}
+
+/// A `sourceMappingURL` comment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceMapComment {
+  pub url: SourceMapUrl,
+  pub style: CommentStyle,
+}
 
 #[derive(Default)]
 pub struct SourceMapOptions {
@@ -43,6 +148,9 @@ pub struct SourceMapOptions {
   /// The filename where you plan to write the sourcemap.
   pub file: Option<String>,
 
+  /// The `sourceRoot` of the generated map, the sources are written relative to it unchanged.
+  pub source_root: Option<String>,
+
   /// The filename of the file containing the original source.
   pub source: Option<String>,
 
@@ -50,5 +158,29 @@ pub struct SourceMapOptions {
   pub include_content: Option<bool>,
 
   /// remap source filename
-  pub remap_source: Option<Box<dyn Fn(&str) -> String>>,
+  pub remap_source: Option<RemapSource>,
+
+  /// Supplies the content of the original sources that are not inlined in the source map chains, reads from disk if `None`.
+  pub source_resolver: Option<Arc<dyn SourceResolver>>,
+
+  /// How the tokens are looked up in the source map chains of the sources.
+  pub lookup_bias: LookupBias,
+
+  /// How to map the tokens that can not be traced through the source map chains of the sources.
+  pub untraceable_token_strategy: UntraceableTokenStrategy,
+
+  /// What happens to the `x_*` fields of the maps of the source map chains.
+  pub extension_fields_policy: ExtensionFieldsPolicy,
+
+  /// Number of threads that trace the tokens through the source map chains, `None` means the available parallelism.
+  /// Only large maps are split across threads, `Some(1)` always traces on the calling thread.
+  pub trace_threads: Option<usize>,
+
+  /// Custom `x_*` fields of the generated map, they replace the fields carried from the source map chains.
+  pub extension_fields: Vec<(String, serde_json::Value)>,
+
+  /// Append a `sourceMappingURL` comment to the code returned together with the map. Only `generate` and
+  /// `generate_to_writer` apply it; `generate_map`, `generate_decoded_map`, `to_string` and `write_to` ignore it, use
+  /// [crate::source_map::GeneratedSourceMap::to_comment] with those.
+  pub url_comment: Option<SourceMapComment>,
 }
@@ -1,6 +1,9 @@
-use std::fmt::{Debug, Display};
+use std::{
+  fmt::{Debug, Display, Write as _},
+  io::Write,
+};
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, Default, PartialEq, Eq)]
 pub struct CharString {
   chars: Vec<char>,
 }
@@ -12,6 +15,10 @@ impl CharString {
     }
   }
 
+  pub fn as_slice(&self) -> &[char] {
+    &self.chars
+  }
+
   pub fn get(&self, index: usize) -> Option<&char> {
     self.chars.get(index)
   }
@@ -50,6 +57,20 @@ impl CharString {
     self.chars.is_empty()
   }
 
+  /// `None` if the string is empty.
+  pub fn non_empty(self) -> Option<Self> {
+    if self.is_empty() {
+      None
+    } else {
+      Some(self)
+    }
+  }
+
+  /// Length of the string in bytes when encoded as utf-8.
+  pub fn byte_len(&self) -> usize {
+    self.chars.iter().map(|char| char.len_utf8()).sum()
+  }
+
   pub fn insert(&mut self, index: usize, char: char) {
     self.chars.insert(index, char);
   }
@@ -69,6 +90,28 @@ impl CharString {
   pub fn append(&mut self, other: &CharString) {
     self.chars.extend(other.chars.iter());
   }
+
+  /// Write the string to `writer` as utf-8.
+  pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> std::io::Result<()> {
+    write_chars(writer, &self.chars)
+  }
+}
+
+/// Write `chars` to `writer` as utf-8, encoded through a buffer on the stack instead of a new [String].
+pub fn write_chars<W: Write + ?Sized>(writer: &mut W, chars: &[char]) -> std::io::Result<()> {
+  let mut buf = [0u8; 1024];
+  let mut len = 0;
+
+  for char in chars {
+    if len + char.len_utf8() > buf.len() {
+      writer.write_all(&buf[..len])?;
+      len = 0;
+    }
+
+    len += char.encode_utf8(&mut buf[len..]).len();
+  }
+
+  writer.write_all(&buf[..len])
 }
 
 impl From<&str> for CharString {
@@ -97,7 +140,7 @@ impl From<char> for CharString {
 
 impl Display for CharString {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "{}", self.chars.iter().collect::<String>())
+    self.chars.iter().try_for_each(|char| f.write_char(*char))
   }
 }
 
@@ -163,4 +206,13 @@ mod tests {
     assert_eq!(result[0].to_string(), "");
     assert_eq!(result[1].to_string(), "");
   }
+
+  #[test]
+  fn test_write_to() {
+    // longer than the write buffer, with multi-byte chars
+    let str = CharString::new(&"€a".repeat(300));
+    let mut buf = vec![];
+    str.write_to(&mut buf).unwrap();
+    assert_eq!(String::from_utf8(buf).unwrap(), str.to_string());
+  }
 }
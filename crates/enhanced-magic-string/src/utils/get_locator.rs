@@ -1,5 +1,7 @@
 use super::char_string::CharString;
 
+pub type Locator = Box<dyn Fn(usize) -> Loc>;
+
 pub fn get_locator(code: &CharString) -> impl Fn(usize) -> Loc {
   let lines = code.split('\n');
   let mut line_offsets = vec![];
@@ -0,0 +1,52 @@
+use super::char_string::CharString;
+
+/// Guess the indent string of the code, `None` if no line is indented.
+/// Tabs win if at least as many lines are indented by tabs as by spaces, otherwise the smallest indent of spaces is used.
+pub fn guess_indent(code: &CharString) -> Option<CharString> {
+  let lines = code.split('\n');
+  let tabbed = lines
+    .iter()
+    .filter(|line| line.get(0) == Some(&'\t'))
+    .count();
+  let spaced = lines
+    .iter()
+    .map(|line| {
+      line
+        .as_slice()
+        .iter()
+        .take_while(|char| **char == ' ')
+        .count()
+    })
+    .filter(|spaces| *spaces >= 2)
+    .collect::<Vec<_>>();
+
+  if tabbed == 0 && spaced.is_empty() {
+    return None;
+  }
+
+  if tabbed >= spaced.len() {
+    return Some(CharString::from('\t'));
+  }
+
+  let min = spaced.into_iter().min().unwrap();
+
+  Some(CharString::new(&" ".repeat(min)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_guess_indent() {
+    assert_eq!(guess_indent(&"a\nb".into()), None);
+    assert_eq!(
+      guess_indent(&"a\n\tb\n  c".into()),
+      Some(CharString::from('\t'))
+    );
+    assert_eq!(
+      guess_indent(&"a\n    b\n  c\n  d".into()),
+      Some(CharString::new("  "))
+    );
+  }
+}
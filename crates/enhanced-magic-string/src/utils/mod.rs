@@ -1,3 +1,6 @@
 pub mod char_string;
 pub mod common;
 pub mod get_locator;
+pub mod guess_indent;
+pub mod source_path;
+pub mod vlq;
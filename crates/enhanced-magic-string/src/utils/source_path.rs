@@ -0,0 +1,269 @@
+//! Normalization of the source paths written to source maps. A source path may be a relative or absolute posix path,
+//! a windows path with a drive letter or a UNC prefix, a `file://` url or an url of another scheme like `webpack://`.
+//! Normalized paths always use `/` as separator.
+
+/// The part of a path that `..` can not go above.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Root {
+  /// a relative path
+  None,
+  /// `/`
+  Slash,
+  /// a windows drive like `C:`, always uppercase
+  Drive(char),
+  /// `//server/share` of a UNC path
+  Unc(String),
+  /// `scheme://authority` of an url, the path after it is always absolute
+  Url(String),
+}
+
+#[derive(Debug)]
+struct SourcePath {
+  root: Root,
+  segments: Vec<String>,
+}
+
+impl SourcePath {
+  fn parse(path: &str) -> Self {
+    if let Some(scheme_end) = url_scheme_end(path) {
+      let scheme = path[..scheme_end].to_ascii_lowercase();
+      let rest = &path[scheme_end + 3..];
+
+      if scheme == "file" {
+        // file:///C:/a, file:///a or file://server/share/a
+        return match rest.strip_prefix('/') {
+          Some(path) if drive_letter(path).is_some() => Self::parse(path),
+          Some(_) => Self::parse(rest),
+          None => Self::parse(&format!("//{rest}")),
+        };
+      }
+
+      let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+
+      return Self {
+        root: Root::Url(format!("{scheme}://{authority}")),
+        segments: normalize_segments(path, true),
+      };
+    }
+
+    let path = path.replace('\\', "/");
+
+    if let Some(drive) = drive_letter(&path) {
+      return Self {
+        root: Root::Drive(drive),
+        segments: normalize_segments(&path[2..], true),
+      };
+    }
+
+    if let Some(unc) = path.strip_prefix("//") {
+      let mut parts = unc.splitn(3, '/');
+      let server = parts.next().unwrap_or_default();
+      let share = parts.next().unwrap_or_default();
+
+      return Self {
+        root: Root::Unc(format!("//{server}/{share}")),
+        segments: normalize_segments(parts.next().unwrap_or_default(), true),
+      };
+    }
+
+    if let Some(path) = path.strip_prefix('/') {
+      return Self {
+        root: Root::Slash,
+        segments: normalize_segments(path, true),
+      };
+    }
+
+    Self {
+      root: Root::None,
+      segments: normalize_segments(&path, false),
+    }
+  }
+
+  fn to_path_string(&self) -> String {
+    let path = self.segments.join("/");
+
+    match &self.root {
+      Root::None => path,
+      Root::Slash => format!("/{path}"),
+      Root::Drive(drive) => format!("{drive}:/{path}"),
+      Root::Unc(prefix) | Root::Url(prefix) => format!("{prefix}/{path}"),
+    }
+  }
+}
+
+/// Byte index of `://` if `path` starts with an url scheme. A single letter is a windows drive, not a scheme.
+fn url_scheme_end(path: &str) -> Option<usize> {
+  let end = path.find("://")?;
+  let scheme = &path[..end];
+
+  (scheme.len() > 1
+    && scheme.starts_with(|char: char| char.is_ascii_alphabetic())
+    && scheme
+      .chars()
+      .all(|char| char.is_ascii_alphanumeric() || matches!(char, '+' | '-' | '.')))
+  .then_some(end)
+}
+
+fn drive_letter(path: &str) -> Option<char> {
+  let mut chars = path.chars();
+  let drive = chars.next()?;
+
+  (drive.is_ascii_alphabetic()
+    && chars.next() == Some(':')
+    && matches!(chars.next(), None | Some('/') | Some('\\')))
+  .then(|| drive.to_ascii_uppercase())
+}
+
+/// Remove the empty and `.` segments and resolve `..`. `..` above the root of an absolute path is dropped.
+fn normalize_segments(path: &str, is_absolute: bool) -> Vec<String> {
+  let mut segments: Vec<String> = vec![];
+
+  for segment in path.split('/') {
+    match segment {
+      "" | "." => {}
+      ".." => match segments.last() {
+        Some(last) if last != ".." => {
+          segments.pop();
+        }
+        _ if is_absolute => {}
+        _ => segments.push("..".to_string()),
+      },
+      segment => segments.push(segment.to_string()),
+    }
+  }
+
+  segments
+}
+
+/// Normalize `path`: use `/` as separator, resolve `.` and `..`, convert `file://` urls to paths and uppercase
+/// windows drive letters.
+pub fn normalize(path: &str) -> String {
+  SourcePath::parse(path).to_path_string()
+}
+
+/// Join `path` to the directory `base`, e.g. a source to the `sourceRoot` of its map. `path` is only normalized if it
+/// has a root itself.
+pub fn join(base: &str, path: &str) -> String {
+  if base.is_empty() || SourcePath::parse(path).root != Root::None {
+    return normalize(path);
+  }
+
+  normalize(&format!("{base}/{path}"))
+}
+
+/// Whether `a` and `b` point at the same file, i.e. their normalized roots and segments are equal, e.g. `./src/a.js`
+/// and `src/a.js`. A relative path never matches an absolute one, as the directory it is relative to is unknown.
+pub fn is_same_file(a: &str, b: &str) -> bool {
+  let a = SourcePath::parse(a);
+  let b = SourcePath::parse(b);
+
+  a.root == b.root && a.segments == b.segments
+}
+
+/// Path of `to` relative to the directory of the file `from`, e.g. a source relative to its source map file.
+/// `to` is returned normalized but not relativized if the two paths do not share a root, like paths on different
+/// drives, an url and a path, or an absolute and a relative path.
+pub fn relative(from: &str, to: &str) -> String {
+  let to_is_dot_relative = to.starts_with("./") || to.starts_with(".\\");
+  let from = SourcePath::parse(from);
+  let to = SourcePath::parse(to);
+
+  if from.root != to.root {
+    return to.to_path_string();
+  }
+
+  let from_dir = &from.segments[..from.segments.len().saturating_sub(1)];
+
+  // same as magic-string, keep the leading `./` of `to` if there is nothing to relativize
+  if from_dir.is_empty() {
+    let path = to.to_path_string();
+
+    return if to_is_dot_relative && !path.is_empty() && !path.starts_with("..") {
+      format!("./{path}")
+    } else {
+      path
+    };
+  }
+
+  let common_len = from_dir
+    .iter()
+    .zip(to.segments.iter())
+    .take_while(|(a, b)| a == b)
+    .count();
+
+  // the directories above the relative `from` are unknown
+  if from_dir[common_len..].iter().any(|segment| segment == "..") {
+    return to.to_path_string();
+  }
+
+  let mut segments = vec![".."; from_dir.len() - common_len];
+  segments.extend(to.segments[common_len..].iter().map(String::as_str));
+
+  segments.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_normalize() {
+    assert_eq!(normalize("./a/../b/./c.js"), "b/c.js");
+    assert_eq!(normalize("../../a.js"), "../../a.js");
+    assert_eq!(normalize("/a/../../b.js"), "/b.js");
+    assert_eq!(normalize("c:\\src\\..\\lib/a.js"), "C:/lib/a.js");
+    assert_eq!(
+      normalize("\\\\server\\share\\a\\b.js"),
+      "//server/share/a/b.js"
+    );
+    assert_eq!(normalize("file:///C:/src/a.js"), "C:/src/a.js");
+    assert_eq!(normalize("file:///home/src/a.js"), "/home/src/a.js");
+    assert_eq!(normalize("file://server/share/a.js"), "//server/share/a.js");
+    assert_eq!(normalize("webpack:///./src/a.js"), "webpack:///src/a.js");
+    assert_eq!(
+      normalize("https://example.com/a/../b.js"),
+      "https://example.com/b.js"
+    );
+  }
+
+  #[test]
+  fn test_join() {
+    assert_eq!(join("src", "./a.ts"), "src/a.ts");
+    assert_eq!(join("/project/src/", "../lib/a.ts"), "/project/lib/a.ts");
+    assert_eq!(join("C:\\project", "src\\a.ts"), "C:/project/src/a.ts");
+    assert_eq!(join("webpack://", "src/a.ts"), "webpack:///src/a.ts");
+    assert_eq!(join("src", "D:/a.ts"), "D:/a.ts");
+    assert_eq!(join("src", "webpack:///a.ts"), "webpack:///a.ts");
+    assert_eq!(join("", "./a.ts"), "a.ts");
+  }
+
+  #[test]
+  fn test_is_same_file() {
+    assert!(is_same_file("./src/a.js", "src/a.js"));
+    assert!(is_same_file(
+      "C:\\project\\src\\a.js",
+      "c:/project/src/./a.js"
+    ));
+    assert!(!is_same_file("a.js", "vendor/a.js"));
+    assert!(!is_same_file("a.js", "/project/src/a.js"));
+    assert!(!is_same_file("b.js", "/project/src/a.js"));
+    assert!(!is_same_file("/other/a.js", "/project/a.js"));
+    assert!(!is_same_file("../a.js", "/project/a.js"));
+  }
+
+  #[test]
+  fn test_relative() {
+    assert_eq!(relative("dist/a.js.map", "src/a.ts"), "../src/a.ts");
+    assert_eq!(relative("C:\\dist\\a.js.map", "c:/src/a.ts"), "../src/a.ts");
+    assert_eq!(relative("C:/dist/a.js.map", "D:/src/a.ts"), "D:/src/a.ts");
+    assert_eq!(
+      relative("/dist/a.js.map", "webpack:///src/a.ts"),
+      "webpack:///src/a.ts"
+    );
+    assert_eq!(relative("dist/a.js.map", "C:\\src\\a.ts"), "C:/src/a.ts");
+    assert_eq!(relative("../a/a.js.map", "b.ts"), "b.ts");
+    assert_eq!(relative("a.js.map", "./src/a.ts"), "./src/a.ts");
+    assert_eq!(relative("dist/a.js.map", "./dist/a.ts"), "a.ts");
+    assert_eq!(relative("a/b/c", "a/b"), "");
+  }
+}
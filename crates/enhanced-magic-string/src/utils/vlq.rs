@@ -0,0 +1,179 @@
+use crate::types::Segment;
+
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Append `value` to `out` as a base64 VLQ.
+pub fn encode_vlq(out: &mut String, value: i64) {
+  let mut value = if value < 0 {
+    ((-value) << 1) | 1
+  } else {
+    value << 1
+  };
+
+  loop {
+    let mut digit = value & 0b11111;
+    value >>= 5;
+
+    if value > 0 {
+      digit |= 0b100000;
+    }
+
+    out.push(BASE64_CHARS[digit as usize] as char);
+
+    if value == 0 {
+      break;
+    }
+  }
+}
+
+/// Encode the segments of each generated line to the `mappings` field of a source map.
+/// Like magic-string, lines are joined by `;` and every line is kept, including the trailing empty ones.
+pub fn encode_mappings<'a>(lines: impl Iterator<Item = &'a [Segment]>) -> String {
+  let mut encoder = MappingsEncoder::default();
+
+  for (line_index, segments) in lines.enumerate() {
+    if line_index > 0 {
+      encoder.next_line();
+    }
+
+    segments.iter().for_each(|segment| encoder.push(segment));
+  }
+
+  encoder.finish()
+}
+
+/// Encodes the `mappings` field segment by segment, for the segments that are not collected per line first.
+#[derive(Default)]
+pub(crate) struct MappingsEncoder {
+  out: String,
+  line_has_segments: bool,
+  generated_column: u32,
+  source_index: u32,
+  original_line: u32,
+  original_column: u32,
+  name_index: u32,
+}
+
+impl MappingsEncoder {
+  pub fn next_line(&mut self) {
+    self.out.push(';');
+    self.line_has_segments = false;
+    self.generated_column = 0;
+  }
+
+  /// Push a segment, one without a source is encoded with only the generated column.
+  pub fn push(&mut self, segment: &Segment) {
+    self.push_generated_column(segment.generated_column);
+
+    let Some(source) = &segment.source else {
+      return;
+    };
+
+    encode_vlq(
+      &mut self.out,
+      source.source_index as i64 - self.source_index as i64,
+    );
+    self.source_index = source.source_index;
+    encode_vlq(
+      &mut self.out,
+      source.original_line as i64 - self.original_line as i64,
+    );
+    self.original_line = source.original_line;
+    encode_vlq(
+      &mut self.out,
+      source.original_column as i64 - self.original_column as i64,
+    );
+    self.original_column = source.original_column;
+
+    if let Some(index) = source.name_index {
+      encode_vlq(&mut self.out, index as i64 - self.name_index as i64);
+      self.name_index = index;
+    }
+  }
+
+  fn push_generated_column(&mut self, generated_column: u32) {
+    if self.line_has_segments {
+      self.out.push(',');
+    }
+
+    self.line_has_segments = true;
+    encode_vlq(
+      &mut self.out,
+      generated_column as i64 - self.generated_column as i64,
+    );
+    self.generated_column = generated_column;
+  }
+
+  pub fn finish(self) -> String {
+    self.out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::types::SegmentSource;
+
+  #[test]
+  fn test_encode_vlq() {
+    let encode = |value| {
+      let mut out = String::new();
+      encode_vlq(&mut out, value);
+      out
+    };
+
+    assert_eq!(encode(0), "A");
+    assert_eq!(encode(1), "C");
+    assert_eq!(encode(-1), "D");
+    assert_eq!(encode(16), "gB");
+    assert_eq!(encode(-255), "/P");
+    assert_eq!(encode(1000), "w+B");
+  }
+
+  #[test]
+  fn test_encode_mappings() {
+    let segment = |generated_column, original_line, original_column| Segment {
+      generated_column,
+      source: Some(SegmentSource {
+        source_index: 0,
+        original_line,
+        original_column,
+        name_index: None,
+      }),
+    };
+    let lines: Vec<Vec<Segment>> = vec![
+      vec![segment(0, 0, 0), segment(6, 0, 6)],
+      vec![],
+      vec![segment(2, 1, 0)],
+      vec![],
+    ];
+
+    assert_eq!(
+      encode_mappings(lines.iter().map(|line| line.as_slice())),
+      "AAAA,MAAM;;EACN;"
+    );
+  }
+
+  #[test]
+  fn test_encode_unmapped() {
+    let unmapped = |generated_column| Segment {
+      generated_column,
+      source: None,
+    };
+    let mut encoder = MappingsEncoder::default();
+    encoder.push(&unmapped(0));
+    encoder.push(&Segment {
+      generated_column: 4,
+      source: Some(SegmentSource {
+        source_index: 0,
+        original_line: 0,
+        original_column: 2,
+        name_index: None,
+      }),
+    });
+    encoder.next_line();
+    encoder.push(&unmapped(3));
+
+    assert_eq!(encoder.finish(), "A,IAAE;G");
+  }
+}
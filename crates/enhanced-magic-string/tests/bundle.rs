@@ -1,9 +1,14 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use enhanced_magic_string::{
-  bundle::BundleOptions,
+  bundle::{
+    AddSourceOptions, BundleOptions, ConcurrentBundleBuilder, DuplicateFilenamePolicy,
+    GeneratedPosition, SplitStrategy,
+  },
+  error::Error,
   magic_string::{MagicString, MagicStringOptions},
-  types::{MappingsOptionHires, SourceMapOptions},
+  source_map::GeneratedCode,
+  types::{CommentStyle, MappingsOptionHires, SourceMapComment, SourceMapOptions, SourceMapUrl},
 };
 use farmfe_utils::relative;
 use parking_lot::Mutex;
@@ -63,12 +68,14 @@ fn bundle() {
     });
 
     bundle.prepend("/* header */\n");
-    bundle.append("//# sourceMappingURL=output.js.map", None);
 
-    let code = bundle.to_string();
-    let map = bundle
-      .generate_map(SourceMapOptions {
+    let GeneratedCode { code, map } = bundle
+      .generate(SourceMapOptions {
         include_content: Some(true),
+        url_comment: Some(SourceMapComment {
+          url: SourceMapUrl::External("output.js.map".to_string()),
+          style: CommentStyle::Js,
+        }),
         ..Default::default()
       })
       .unwrap();
@@ -82,7 +89,7 @@ fn bundle() {
     let expected_map = std::fs::read_to_string(dir.join("output.js.map")).unwrap();
     assert_eq!(
       normalize_newlines(&map_str),
-      normalize_newlines(&expected_map.replace(";\"}", "\"}"))
+      normalize_newlines(&expected_map)
     );
   });
 }
@@ -117,6 +124,759 @@ fn bundle_multi_thread() {
   assert_eq!(code, "/* a */\n/* b */");
 }
 
+#[test]
+fn bundle_shared_source() {
+  let a = Arc::new(MagicString::new(
+    "export const a = 1;",
+    Some(MagicStringOptions {
+      filename: Some("a.js".to_string()),
+      ..Default::default()
+    }),
+  ));
+  let b = Arc::new(MagicString::new(
+    "export const b = 2;",
+    Some(MagicStringOptions {
+      filename: Some("b.js".to_string()),
+      ..Default::default()
+    }),
+  ));
+
+  let mut esm = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+  esm.add_source(a.clone(), None).unwrap();
+  esm.add_source(b.clone(), None).unwrap();
+
+  let mut cjs = std::thread::scope(|s| {
+    s.spawn(|| {
+      let mut cjs = enhanced_magic_string::bundle::Bundle::new(BundleOptions {
+        separator: Some(";".into()),
+        ..Default::default()
+      });
+
+      for source in [&a, &b] {
+        cjs
+          .add_source(
+            source.clone(),
+            Some(AddSourceOptions {
+              separator: Some(";".into()),
+              intro: Some("(function(){".into()),
+              outro: Some("})()".into()),
+              ..Default::default()
+            }),
+          )
+          .unwrap();
+      }
+
+      cjs
+    })
+    .join()
+    .unwrap()
+  });
+  cjs.prepend("/* cjs */");
+
+  assert_eq!(esm.to_string(), "export const a = 1;\nexport const b = 2;");
+  assert_eq!(
+    cjs.to_string(),
+    "/* cjs */(function(){export const a = 1;})();(function(){export const b = 2;})()"
+  );
+  assert_eq!(a.to_string(), "export const a = 1;");
+
+  let map = cjs.generate_map(SourceMapOptions::default()).unwrap();
+  assert_eq!(map.sources().collect::<Vec<_>>(), vec!["a.js", "b.js"]);
+  let token = map.lookup_token(0, 57).unwrap();
+  assert_eq!(token.get_source(), Some("b.js"));
+  assert_eq!(token.get_dst(), (0, 57));
+}
+
+#[test]
+fn bundle_duplicate_filename_policy() {
+  let new_bundle = |policy| {
+    let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions {
+      duplicate_filename_policy: policy,
+      ..Default::default()
+    });
+
+    for content in ["const client = 1;", "const ssr = 1;", "const client = 1;"] {
+      let m = MagicString::new(
+        content,
+        Some(MagicStringOptions {
+          filename: Some("a.js".to_string()),
+          ..Default::default()
+        }),
+      );
+      bundle.add_source(m, None)?;
+    }
+
+    Ok::<_, enhanced_magic_string::error::Error>(bundle)
+  };
+  let sources_of = |bundle: enhanced_magic_string::bundle::Bundle| {
+    let map = bundle
+      .generate_map(SourceMapOptions {
+        include_content: Some(true),
+        ..Default::default()
+      })
+      .unwrap();
+    let tokens = map
+      .tokens()
+      .map(|token| token.get_source().unwrap().to_string())
+      .collect::<Vec<_>>();
+    let sources = map
+      .sources()
+      .zip(map.source_contents())
+      .map(|(source, content)| (source.to_string(), content.unwrap().to_string()))
+      .collect::<Vec<_>>();
+
+    (sources, tokens)
+  };
+
+  assert!(new_bundle(None).is_err());
+
+  let (sources, tokens) =
+    sources_of(new_bundle(Some(DuplicateFilenamePolicy::Disambiguate)).unwrap());
+  assert_eq!(
+    sources,
+    vec![
+      ("a.js".to_string(), "const client = 1;".to_string()),
+      ("a.js?v=2".to_string(), "const ssr = 1;".to_string())
+    ]
+  );
+  assert_eq!(tokens, vec!["a.js", "a.js?v=2", "a.js"]);
+
+  let (sources, tokens) = sources_of(new_bundle(Some(DuplicateFilenamePolicy::LastWins)).unwrap());
+  assert_eq!(
+    sources,
+    vec![("a.js".to_string(), "const client = 1;".to_string())]
+  );
+  assert_eq!(tokens, vec!["a.js", "a.js", "a.js"]);
+}
+
+#[test]
+fn bundle_last_wins_keeps_source_chains() {
+  let new_bundle = |trace_source_map_chain| {
+    let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions {
+      duplicate_filename_policy: Some(DuplicateFilenamePolicy::LastWins),
+      trace_source_map_chain: Some(trace_source_map_chain),
+      ..Default::default()
+    });
+
+    for (content, chain_map) in [
+      (
+        "const client = 1;",
+        r#"{"version":3,"sources":["client.ts"],"names":[],"mappings":"AACE"}"#,
+      ),
+      (
+        "const ssr = 1;",
+        r#"{"version":3,"sources":["ssr.ts"],"names":[],"mappings":"AAAA"}"#,
+      ),
+    ] {
+      let m = MagicString::new(
+        content,
+        Some(MagicStringOptions {
+          filename: Some("a.js".to_string()),
+          source_map_chain: vec![Arc::new(chain_map.to_string())],
+          ..Default::default()
+        }),
+      );
+      bundle.add_source(m, None).unwrap();
+    }
+
+    bundle
+  };
+  let opts = || SourceMapOptions {
+    include_content: Some(true),
+    ..Default::default()
+  };
+
+  // the sources share one entry with the content of the last one
+  let map = new_bundle(false).generate_map(opts()).unwrap();
+  assert_eq!(map.sources().collect::<Vec<_>>(), vec!["a.js"]);
+  assert_eq!(
+    map.source_contents().collect::<Vec<_>>(),
+    vec![Some("const ssr = 1;")]
+  );
+  assert_eq!(
+    map
+      .tokens()
+      .map(|token| (token.get_dst_line(), token.get_src_id()))
+      .collect::<Vec<_>>(),
+    vec![(0, 0), (1, 0)]
+  );
+
+  // the tokens of each source are traced through its own chain
+  let map = new_bundle(true).generate_map(opts()).unwrap();
+  let token = map.lookup_token(0, 0).unwrap();
+  assert_eq!(token.get_source(), Some("client.ts"));
+  assert_eq!(token.get_src(), (1, 2));
+  let token = map.lookup_token(1, 0).unwrap();
+  assert_eq!(token.get_source(), Some("ssr.ts"));
+  assert_eq!(token.get_src(), (0, 0));
+}
+
+#[test]
+fn bundle_disambiguate_existing_filename() {
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions {
+    duplicate_filename_policy: Some(DuplicateFilenamePolicy::Disambiguate),
+    ..Default::default()
+  });
+
+  for (filename, content) in [
+    ("a.js?v=2", "const real = 1;"),
+    ("a.js", "const client = 1;"),
+    ("a.js", "const real = 1;"),
+  ] {
+    let m = MagicString::new(
+      content,
+      Some(MagicStringOptions {
+        filename: Some(filename.to_string()),
+        ..Default::default()
+      }),
+    );
+    bundle.add_source(m, None).unwrap();
+  }
+
+  let map = bundle.generate_map(SourceMapOptions::default()).unwrap();
+  assert_eq!(
+    map.sources().collect::<Vec<_>>(),
+    vec!["a.js?v=2", "a.js", "a.js?v=3"]
+  );
+}
+
+#[test]
+fn bundle_virtual_sources() {
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions {
+    virtual_sources: Some(true),
+    ..Default::default()
+  });
+  bundle
+    .add_source(MagicString::new("function __helper() {}", None), None)
+    .unwrap();
+  bundle
+    .add_source(
+      MagicString::new(
+        "const a = 1;",
+        Some(MagicStringOptions {
+          filename: Some("a.js".to_string()),
+          ..Default::default()
+        }),
+      ),
+      None,
+    )
+    .unwrap();
+  bundle
+    .add_source(
+      MagicString::new("__helper();", None),
+      Some(AddSourceOptions {
+        separator: Some("\n".into()),
+        virtual_filename: Some("farm:runtime".to_string()),
+        ..Default::default()
+      }),
+    )
+    .unwrap();
+  bundle
+    .add_source(MagicString::new("export {};", None), None)
+    .unwrap();
+
+  let map = bundle.generate_map(SourceMapOptions::default()).unwrap();
+  assert_eq!(
+    map.sources().collect::<Vec<_>>(),
+    vec!["farm:virtual/0", "a.js", "farm:runtime", "farm:virtual/1"]
+  );
+  // virtual sources inline their content by default
+  assert_eq!(
+    map.source_contents().collect::<Vec<_>>(),
+    vec![
+      Some("function __helper() {}"),
+      None,
+      Some("__helper();"),
+      Some("export {};")
+    ]
+  );
+  let token = map.lookup_token(2, 0).unwrap();
+  assert_eq!(token.get_source(), Some("farm:runtime"));
+
+  // appended code is not a virtual source
+  bundle.append("\n//# footer", None);
+  let map = bundle.generate_map(SourceMapOptions::default()).unwrap();
+  assert_eq!(map.get_source_count(), 4);
+
+  // the content of virtual sources can be left out
+  let map = bundle
+    .generate_map(SourceMapOptions {
+      include_content: Some(false),
+      ..Default::default()
+    })
+    .unwrap();
+  assert!(map.source_contents().all(|content| content.is_none()));
+}
+
+#[test]
+fn bundle_multi_line_separator() {
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions {
+    separator: Some(";\n\n".into()),
+    ..Default::default()
+  });
+
+  for (filename, content) in [("a.js", "a()"), ("b.js", "b()\nc()")] {
+    let m = MagicString::new(
+      content,
+      Some(MagicStringOptions {
+        filename: Some(filename.to_string()),
+        ..Default::default()
+      }),
+    );
+    bundle.add_source(m, None).unwrap();
+  }
+  bundle.append("\n//# sourceMappingURL=index.js.map", None);
+
+  assert_eq!(
+    bundle.to_string(),
+    "a();\n\nb()\nc()\n//# sourceMappingURL=index.js.map"
+  );
+
+  let map = bundle.generate_map(SourceMapOptions::default()).unwrap();
+  let tokens = map
+    .tokens()
+    .map(|token| {
+      (
+        token.get_dst(),
+        token.get_source().unwrap(),
+        token.get_src(),
+      )
+    })
+    .collect::<Vec<_>>();
+  assert_eq!(
+    tokens,
+    vec![
+      ((0, 0), "a.js", (0, 0)),
+      ((2, 0), "b.js", (0, 0)),
+      ((3, 0), "b.js", (1, 0))
+    ]
+  );
+}
+
+#[test]
+fn bundle_inherited_separator() {
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+
+  for (filename, content, separator) in [
+    ("a.js", "a()", None),
+    ("b.js", "b()", None),
+    ("c.js", "c()", Some("".into())),
+  ] {
+    bundle
+      .add_source(
+        MagicString::new(content, None),
+        Some(AddSourceOptions {
+          filename: Some(filename.to_string()),
+          separator,
+          ..Default::default()
+        }),
+      )
+      .unwrap();
+  }
+
+  // `None` inherits the separator of the bundle, an empty separator glues the sources
+  assert_eq!(bundle.to_string(), "a()\nb()c()");
+}
+
+#[test]
+fn bundle_per_source_options() {
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+  let app = MagicString::new(
+    "let a = 1;",
+    Some(MagicStringOptions {
+      filename: Some("src/app.js".to_string()),
+      ..Default::default()
+    }),
+  );
+  let vendor = MagicString::new(
+    "let v = 1;",
+    Some(MagicStringOptions {
+      filename: Some("node_modules/vendor.js".to_string()),
+      ..Default::default()
+    }),
+  );
+  bundle.add_source(app, None).unwrap();
+  bundle
+    .add_source(
+      vendor,
+      Some(AddSourceOptions {
+        separator: Some("\n".into()),
+        hires: Some(MappingsOptionHires::Bool(false)),
+        include_content: Some(false),
+        ignore: Some(true),
+        ..Default::default()
+      }),
+    )
+    .unwrap();
+
+  let map = bundle
+    .generate_map(SourceMapOptions {
+      hires: Some(MappingsOptionHires::Bool(true)),
+      include_content: Some(true),
+      ..Default::default()
+    })
+    .unwrap();
+  let token_count_of = |src_id: u32| {
+    map
+      .tokens()
+      .filter(|token| token.get_src_id() == src_id)
+      .count()
+  };
+  assert_eq!(token_count_of(0), 10);
+  assert_eq!(token_count_of(1), 1);
+  assert_eq!(
+    map.source_contents().collect::<Vec<_>>(),
+    vec![Some("let a = 1;"), None]
+  );
+  assert_eq!(map.ignore_list(), &[1]);
+
+  let mut src_buf = vec![];
+  map.to_writer(&mut src_buf).unwrap();
+  let map_str = String::from_utf8(src_buf).unwrap();
+  assert!(map_str.ends_with(",\"x_google_ignoreList\":[1]}"));
+}
+
+#[test]
+fn bundle_source_ranges() {
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+
+  for filename in ["a.js", "b.js", "c.js"] {
+    let m = MagicString::new(
+      "let a = 1;",
+      Some(MagicStringOptions {
+        filename: Some(filename.to_string()),
+        ..Default::default()
+      }),
+    );
+    bundle
+      .add_source(
+        m,
+        Some(AddSourceOptions {
+          separator: Some("\n".into()),
+          ..Default::default()
+        }),
+      )
+      .unwrap();
+  }
+
+  let map = bundle
+    .generate_map(SourceMapOptions {
+      hires: Some(MappingsOptionHires::SourceRanges(HashMap::from([
+        ("a.js".to_string(), vec![(4, 6)]),
+        ("b.js".to_string(), vec![(8, 10)]),
+      ]))),
+      ..Default::default()
+    })
+    .unwrap();
+  let columns_of = |src_id: u32| {
+    map
+      .tokens()
+      .filter(|token| token.get_src_id() == src_id)
+      .map(|token| token.get_src_col())
+      .collect::<Vec<_>>()
+  };
+  assert_eq!(columns_of(0), vec![0, 4, 5]);
+  assert_eq!(columns_of(1), vec![0, 8, 9]);
+  // c.js has no ranges
+  assert_eq!(columns_of(2), vec![0]);
+}
+
+#[test]
+fn bundle_indent_wrap_trim() {
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+
+  for (filename, content) in [
+    ("a.js", "\n\nfunction a() {\n  return 1;\n}"),
+    ("b.js", "const b = `\nb`;\n\n"),
+  ] {
+    let m = MagicString::new(
+      content,
+      Some(MagicStringOptions {
+        filename: Some(filename.to_string()),
+        indent_exclusion_ranges: if filename == "b.js" {
+          vec![(11, 13)]
+        } else {
+          vec![]
+        },
+        ..Default::default()
+      }),
+    );
+    bundle.add_source(m, None).unwrap();
+  }
+
+  assert_eq!(bundle.get_indent_string().to_string(), "  ");
+
+  bundle.trim();
+  bundle.indent(None);
+  bundle.wrap("(function () {\n", "\n})();");
+
+  assert_eq!(
+    bundle.to_string(),
+    "(function () {\n  function a() {\n    return 1;\n  }\n  const b = `\nb`;\n})();"
+  );
+
+  let map = bundle
+    .generate_map(SourceMapOptions {
+      hires: Some(MappingsOptionHires::Bool(true)),
+      ..Default::default()
+    })
+    .unwrap();
+  let lookup = |line, col| {
+    let token = map.lookup_token(line, col).unwrap();
+    (
+      token.get_dst(),
+      token.get_source().unwrap().to_string(),
+      token.get_src(),
+    )
+  };
+  assert_eq!(lookup(1, 2), ((1, 2), "a.js".to_string(), (2, 0)));
+  assert_eq!(lookup(2, 4), ((2, 4), "a.js".to_string(), (3, 2)));
+  assert_eq!(lookup(4, 2), ((4, 2), "b.js".to_string(), (0, 0)));
+  assert_eq!(lookup(5, 0), ((5, 0), "b.js".to_string(), (1, 0)));
+  assert_eq!(map.lookup_token(6, 0).unwrap().get_dst_line(), 5);
+}
+
+#[test]
+fn bundle_nested_indent() {
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+  let m = MagicString::new(
+    "a();\nb();",
+    Some(MagicStringOptions {
+      filename: Some("a.js".to_string()),
+      ..Default::default()
+    }),
+  );
+  bundle.add_source(m, None).unwrap();
+
+  bundle.indent(Some("  "));
+  bundle.indent(Some("\t"));
+
+  assert_eq!(bundle.to_string(), "\t  a();\n\t  b();");
+  let map = bundle.generate_map(SourceMapOptions::default()).unwrap();
+  assert_eq!(
+    map
+      .tokens()
+      .map(|token| (token.get_dst(), token.get_src()))
+      .collect::<Vec<_>>(),
+    vec![((0, 3), (0, 0)), ((1, 3), (1, 0))]
+  );
+}
+
+#[test]
+fn bundle_manifest() {
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+  let mut a = MagicString::new(
+    "const a = '€';",
+    Some(MagicStringOptions {
+      filename: Some("a.js".to_string()),
+      ..Default::default()
+    }),
+  );
+  a.prepend("/* a */\n");
+  bundle.add_source(a, None).unwrap();
+  bundle
+    .add_source(
+      MagicString::new(
+        "b()\nc()",
+        Some(MagicStringOptions {
+          filename: Some("b.js".to_string()),
+          ..Default::default()
+        }),
+      ),
+      Some(AddSourceOptions {
+        separator: Some(";\n".into()),
+        intro: Some("(".into()),
+        outro: Some(")".into()),
+        ..Default::default()
+      }),
+    )
+    .unwrap();
+  bundle.prepend("/* header */\n");
+
+  let code = bundle.to_string();
+  let manifest = bundle
+    .manifest(SourceMapOptions {
+      hires: Some(MappingsOptionHires::Bool(true)),
+      ..Default::default()
+    })
+    .unwrap();
+  assert_eq!(manifest.size, code.len());
+  assert_eq!(manifest.bundle_size, 13);
+  assert_eq!(manifest.sources.len(), 2);
+
+  let a = &manifest.sources[0];
+  assert_eq!(a.filename.as_deref(), Some("a.js"));
+  assert_eq!(
+    a.start,
+    GeneratedPosition {
+      line: 1,
+      column: 0,
+      offset: 13
+    }
+  );
+  assert_eq!(
+    a.end,
+    GeneratedPosition {
+      line: 2,
+      column: 14,
+      offset: 37
+    }
+  );
+  assert_eq!(
+    (a.separator_size, a.wrapper_size, a.content_size),
+    (0, 8, 16)
+  );
+  assert_eq!(a.original_sizes, vec![("a.js".to_string(), 16)]);
+
+  let b = &manifest.sources[1];
+  assert_eq!(
+    b.start,
+    GeneratedPosition {
+      line: 3,
+      column: 0,
+      offset: 39
+    }
+  );
+  assert_eq!(b.end.offset, code.len());
+  assert_eq!(
+    (b.separator_size, b.wrapper_size, b.content_size),
+    (2, 2, 7)
+  );
+  // the span of the last token of a line reaches the end of the line
+  assert_eq!(b.original_sizes, vec![("b.js".to_string(), 7)]);
+}
+
+#[test]
+fn bundle_split() {
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+
+  for (filename, content) in [
+    ("a.js", "const a = 1;"),
+    ("b.js", "const b = 2;"),
+    ("c.js", "const c = 3;"),
+  ] {
+    let m = MagicString::new(
+      content,
+      Some(MagicStringOptions {
+        filename: Some(filename.to_string()),
+        ..Default::default()
+      }),
+    );
+    bundle.add_source(m, None).unwrap();
+  }
+  bundle.prepend("/* header */\n");
+
+  let outputs = bundle
+    .split(SplitStrategy::Groups(vec![vec![2, 0], vec![1]]))
+    .unwrap();
+  assert_eq!(outputs.len(), 2);
+  assert_eq!(
+    outputs[0].to_string(),
+    "/* header */\nconst c = 3;\nconst a = 1;"
+  );
+  assert_eq!(outputs[1].to_string(), "/* header */\nconst b = 2;");
+
+  let map = outputs[0]
+    .generate_map(SourceMapOptions {
+      include_content: Some(true),
+      ..Default::default()
+    })
+    .unwrap();
+  assert_eq!(map.sources().collect::<Vec<_>>(), vec!["c.js", "a.js"]);
+  assert_eq!(
+    map.source_contents().collect::<Vec<_>>(),
+    vec![Some("const c = 3;"), Some("const a = 1;")]
+  );
+  let token = map.lookup_token(2, 0).unwrap();
+  assert_eq!(token.get_source(), Some("a.js"));
+  assert_eq!(token.get_src(), (0, 0));
+
+  let split_indexes = |max_size| {
+    bundle
+      .split(SplitStrategy::MaxSize(max_size))
+      .unwrap()
+      .iter()
+      .map(|output| output.source_indexes().to_vec())
+      .collect::<Vec<_>>()
+  };
+  // the 13 bytes of the header count towards every output
+  assert_eq!(split_indexes(38), vec![vec![0, 1], vec![2]]);
+  assert_eq!(split_indexes(37), vec![vec![0], vec![1], vec![2]]);
+  assert!(bundle
+    .split(SplitStrategy::MaxSize(38))
+    .unwrap()
+    .iter()
+    .all(|output| output.to_string().len() <= 38));
+
+  assert!(matches!(
+    bundle.split(SplitStrategy::Groups(vec![vec![3]])),
+    Err(Error::SourceIndexOutOfBounds(3))
+  ));
+  assert!(matches!(
+    bundle.split(SplitStrategy::Groups(vec![vec![0], vec![]])),
+    Err(Error::EmptySplitGroup(1))
+  ));
+  assert!(matches!(
+    bundle.split(SplitStrategy::Groups(vec![vec![0, 1], vec![1]])),
+    Err(Error::DuplicateSourceIndex(1))
+  ));
+}
+
+#[test]
+fn bundle_concurrent_builder() {
+  let builder = ConcurrentBundleBuilder::new(BundleOptions::default());
+
+  std::thread::scope(|s| {
+    for thread in 0..4 {
+      let builder = &builder;
+
+      s.spawn(move || {
+        for i in (thread * 25..(thread + 1) * 25).rev() {
+          let m = MagicString::new(
+            &format!("m{i}"),
+            Some(MagicStringOptions {
+              filename: Some(format!("m{i}.js")),
+              ..Default::default()
+            }),
+          );
+          // the module graph decides the order, the reverse of the transform order here
+          builder.add_source(100 - i, m, None);
+        }
+      });
+    }
+  });
+
+  let bundle = builder.build().unwrap();
+  let expected = (0..100)
+    .rev()
+    .map(|i| format!("m{i}"))
+    .collect::<Vec<_>>()
+    .join("\n");
+  assert_eq!(bundle.to_string(), expected);
+
+  let map = bundle.generate_map(SourceMapOptions::default()).unwrap();
+  assert_eq!(map.get_source(0), Some("m99.js"));
+  assert_eq!(map.get_source(99), Some("m0.js"));
+}
+
+#[test]
+fn bundle_concurrent_builder_equal_keys() {
+  let builder = ConcurrentBundleBuilder::new(BundleOptions::default());
+
+  // equal keys added one after another from different threads, which may use any shards
+  for content in ["a()", "b()", "c()", "d()"] {
+    std::thread::scope(|s| {
+      s.spawn(|| builder.add_source(0, MagicString::new(content, None), None));
+    });
+  }
+  builder.add_source(0, MagicString::new("e()", None), None);
+
+  assert_eq!(
+    builder.build().unwrap().to_string(),
+    "a()\nb()\nc()\nd()\ne()"
+  );
+}
+
 #[test]
 fn combine_string_with_original_sourcemap() {
   fixture!("tests/fixtures/combine-string/**/input.js", |file, _| {
@@ -133,7 +893,7 @@ fn combine_string_with_original_sourcemap() {
       for path in paths {
         let path = path.unwrap();
         let content = std::fs::read_to_string(&path).unwrap();
-        let sourcemap_content = std::fs::read_to_string(&path.with_extension("js.map")).unwrap();
+        let sourcemap_content = std::fs::read_to_string(path.with_extension("js.map")).unwrap();
         let mut m = MagicString::new(
           &content,
           Some(MagicStringOptions {
@@ -152,7 +912,7 @@ fn combine_string_with_original_sourcemap() {
     }
 
     let file_content = std::fs::read_to_string(&file).unwrap();
-    let sourcemap_content = std::fs::read_to_string(&file.with_extension("js.map")).unwrap();
+    let sourcemap_content = std::fs::read_to_string(file.with_extension("js.map")).unwrap();
     let magic_string = MagicString::new(
       &file_content,
       Some(MagicStringOptions {
@@ -189,17 +949,658 @@ fn combine_string_with_original_sourcemap() {
     map.to_writer(&mut src_buf).unwrap();
     let map_str = String::from_utf8(src_buf).unwrap();
 
-    std::fs::write(dir.join("output.js"), &code).unwrap();
-
     let expected = std::fs::read_to_string(dir.join("output.js")).unwrap();
     assert_eq!(normalize_newlines(&code), normalize_newlines(&expected));
 
-    std::fs::write(dir.join("output.js.map"), &map_str).unwrap();
-
     let expected_map = std::fs::read_to_string(dir.join("output.js.map")).unwrap();
     assert_eq!(
       normalize_newlines(&map_str),
-      normalize_newlines(&expected_map.replace(";\"}", "\"}"))
+      normalize_newlines(&expected_map)
+    );
+  });
+}
+
+#[test]
+fn bundle_generate() {
+  struct FailingWriter;
+
+  impl std::io::Write for FailingWriter {
+    fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+      Err(std::io::Error::other("closed"))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+
+  for (filename, content) in [("a.js", "const a = '€';\n  a();"), ("b.js", "b()")] {
+    let mut m = MagicString::new(
+      content,
+      Some(MagicStringOptions {
+        filename: Some(filename.to_string()),
+        ..Default::default()
+      }),
+    );
+    m.prepend("/* module */\n");
+    bundle.add_source(m, None).unwrap();
+  }
+  bundle.prepend("/* header */\n");
+  bundle.indent(None);
+  bundle.trim();
+
+  let opts = || SourceMapOptions {
+    hires: Some(MappingsOptionHires::Boundary),
+    include_content: Some(true),
+    ..Default::default()
+  };
+  let to_json = |map: &enhanced_magic_string::source_map::GeneratedSourceMap| {
+    let mut buf = vec![];
+    map.to_writer(&mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+  };
+
+  let generated = bundle.generate(opts()).unwrap();
+  assert_eq!(generated.code, bundle.to_string());
+  assert_eq!(
+    to_json(&generated.map),
+    to_json(&bundle.generate_map(opts()).unwrap())
+  );
+
+  let mut code = vec![];
+  let map = bundle.generate_to_writer(&mut code, opts()).unwrap();
+  assert_eq!(String::from_utf8(code).unwrap(), generated.code);
+  assert_eq!(to_json(&map), to_json(&generated.map));
+
+  assert!(matches!(
+    bundle.generate_to_writer(FailingWriter, opts()),
+    Err(enhanced_magic_string::error::Error::Io(_))
+  ));
+
+  let outputs = bundle.split(SplitStrategy::Groups(vec![vec![1]])).unwrap();
+  let generated = outputs[0].generate(opts()).unwrap();
+  assert_eq!(generated.code, outputs[0].to_string());
+}
+
+#[test]
+fn bundle_write_to() {
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+
+  for content in ["const a = '€';", "const b = 2;"] {
+    let mut m = MagicString::new(content, None);
+    m.prepend("/* module */\n");
+    bundle.add_source(m, None).unwrap();
+  }
+  bundle.wrap("(function () {\n", "\n})();");
+  bundle.indent(Some("  "));
+
+  let mut code = vec![];
+  bundle.write_to(&mut code).unwrap();
+  let code = String::from_utf8(code).unwrap();
+  assert_eq!(
+    code,
+    "(function () {\n  /* module */\n  const a = '€';\n  /* module */\n  const b = 2;\n})();"
+  );
+  assert_eq!(format!("{bundle}"), code);
+
+  let outputs = bundle.split(SplitStrategy::Groups(vec![vec![1]])).unwrap();
+  let mut output_code = vec![];
+  outputs[0].write_to(&mut output_code).unwrap();
+  assert_eq!(
+    String::from_utf8(output_code).unwrap(),
+    outputs[0].to_string()
+  );
+}
+
+#[test]
+fn bundle_decoded_map() {
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+
+  for (filename, content) in [("a.js", "a()"), ("b.js", "b()")] {
+    let m = MagicString::new(
+      content,
+      Some(MagicStringOptions {
+        filename: Some(filename.to_string()),
+        ..Default::default()
+      }),
+    );
+    bundle.add_source(m, None).unwrap();
+  }
+
+  let map = bundle.generate_map(SourceMapOptions::default()).unwrap();
+  let decoded = bundle
+    .generate_decoded_map(SourceMapOptions::default())
+    .unwrap();
+  assert_eq!(
+    decoded.sources,
+    vec!["a.js".to_string(), "b.js".to_string()]
+  );
+  assert_eq!(decoded.mappings, map.decoded_mappings());
+  assert_eq!(
+    decoded
+      .mappings
+      .iter()
+      .map(|line| {
+        line
+          .iter()
+          .map(|s| s.source.unwrap().source_index)
+          .collect::<Vec<_>>()
+      })
+      .collect::<Vec<_>>(),
+    vec![vec![0], vec![1]]
+  );
+}
+
+#[test]
+fn bundle_source_map_url_comment() {
+  use base64::Engine;
+  use enhanced_magic_string::source_map::{SourceMapJsonOptions, DATA_URL_PREFIX};
+
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+  bundle
+    .add_source(
+      MagicString::new(
+        "a()",
+        Some(MagicStringOptions {
+          filename: Some("a.js".to_string()),
+          ..Default::default()
+        }),
+      ),
+      None,
+    )
+    .unwrap();
+
+  let generate = |url, style| {
+    bundle
+      .generate(SourceMapOptions {
+        url_comment: Some(SourceMapComment { url, style }),
+        ..Default::default()
+      })
+      .unwrap()
+  };
+
+  let generated = generate(
+    SourceMapUrl::External("a.js.map".to_string()),
+    CommentStyle::Js,
+  );
+  assert_eq!(generated.code, "a()\n//# sourceMappingURL=a.js.map");
+
+  let generated = generate(
+    SourceMapUrl::External("a.css.map".to_string()),
+    CommentStyle::Css,
+  );
+  assert_eq!(generated.code, "a()\n/*# sourceMappingURL=a.css.map */");
+
+  let generated = generate(SourceMapUrl::Inline, CommentStyle::Js);
+  let url = generated
+    .code
+    .strip_prefix("a()\n//# sourceMappingURL=")
+    .unwrap();
+  assert_eq!(url, generated.map.to_url());
+  let json = base64::engine::general_purpose::STANDARD
+    .decode(url.strip_prefix(DATA_URL_PREFIX).unwrap())
+    .unwrap();
+  assert_eq!(
+    String::from_utf8(json).unwrap(),
+    generated.map.to_json(&SourceMapJsonOptions::default())
+  );
+
+  let mut code = vec![];
+  bundle
+    .generate_to_writer(
+      &mut code,
+      SourceMapOptions {
+        url_comment: Some(SourceMapComment {
+          url: SourceMapUrl::External("a.js.map".to_string()),
+          style: CommentStyle::Js,
+        }),
+        ..Default::default()
+      },
+    )
+    .unwrap();
+  assert_eq!(
+    String::from_utf8(code).unwrap(),
+    "a()\n//# sourceMappingURL=a.js.map"
+  );
+
+  let m = MagicString::new("b()", None);
+  let generated = m
+    .generate(SourceMapOptions {
+      url_comment: Some(SourceMapComment {
+        url: SourceMapUrl::External("b.js.map".to_string()),
+        style: CommentStyle::Js,
+      }),
+      ..Default::default()
+    })
+    .unwrap();
+  assert_eq!(generated.code, "b()\n//# sourceMappingURL=b.js.map");
+}
+
+#[test]
+fn bundle_source_resolver() {
+  use std::{collections::HashMap, path::PathBuf};
+
+  let resolver = Arc::new(HashMap::from([
+    (PathBuf::from("a.ts"), "const a: number = 1;".to_string()),
+    (
+      PathBuf::from("dist/a.js.map"),
+      r#"{"version":3,"file":"a.js","sources":["a.ts"],"names":[],"mappings":"AAAA"}"#.to_string(),
+    ),
+  ]));
+  let m = MagicString::new(
+    "const a = 1;\n//# sourceMappingURL=a.js.map",
+    Some(MagicStringOptions {
+      filename: Some("dist/a.js".to_string()),
+      extract_source_map: true,
+      source_resolver: Some(resolver.clone()),
+      ..Default::default()
+    }),
+  );
+  assert_eq!(m.get_source_map_chain().len(), 1);
+
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions {
+    trace_source_map_chain: Some(true),
+    ..Default::default()
+  });
+  bundle.add_source(m, None).unwrap();
+
+  let map = bundle
+    .generate_map(SourceMapOptions {
+      include_content: Some(true),
+      source_resolver: Some(resolver),
+      ..Default::default()
+    })
+    .unwrap();
+  assert_eq!(map.get_source(0), Some("a.ts"));
+  assert_eq!(map.get_source_contents(0), Some("const a: number = 1;"));
+}
+
+#[test]
+fn bundle_source_paths() {
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+
+  for (filename, content) in [
+    ("C:\\project\\src\\a.js", "a()"),
+    ("c:/project/src/../lib/./b.js", "b()"),
+    ("D:\\other\\c.js", "c()"),
+    ("webpack:///./src/d.js", "d()"),
+  ] {
+    let m = MagicString::new(
+      content,
+      Some(MagicStringOptions {
+        filename: Some(filename.to_string()),
+        ..Default::default()
+      }),
+    );
+    bundle.add_source(m, None).unwrap();
+  }
+
+  let map = bundle
+    .generate_map(SourceMapOptions {
+      file: Some("C:\\project\\dist\\index.js".to_string()),
+      remap_source: Some(Box::new(|src| src.replace("webpack:///", "/"))),
+      ..Default::default()
+    })
+    .unwrap();
+  assert_eq!(
+    map.sources().collect::<Vec<_>>(),
+    vec!["../src/a.js", "../lib/b.js", "D:/other/c.js", "/src/d.js"]
+  );
+}
+
+#[test]
+fn bundle_source_root() {
+  use std::{collections::HashMap, path::PathBuf};
+
+  let resolver = Arc::new(HashMap::from([(
+    PathBuf::from("C:/project/src/a.ts"),
+    "const a: number = 1;".to_string(),
+  )]));
+  let m = MagicString::new(
+    "const a = 1;\n//# sourceMappingURL=data:application/json;base64,eyJ2ZXJzaW9uIjozLCJzb3VyY2VSb290IjoiQzpcXHByb2plY3RcXHNyYyIsInNvdXJjZXMiOlsiLi9hLnRzIl0sIm5hbWVzIjpbXSwibWFwcGluZ3MiOiJBQUFBIn0=",
+    Some(MagicStringOptions {
+      filename: Some("a.js".to_string()),
+      extract_source_map: true,
+      ..Default::default()
+    }),
+  );
+
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions {
+    trace_source_map_chain: Some(true),
+    ..Default::default()
+  });
+  bundle.add_source(m, None).unwrap();
+
+  let map = bundle
+    .generate_map(SourceMapOptions {
+      include_content: Some(true),
+      source_root: Some("/".to_string()),
+      source_resolver: Some(resolver),
+      ..Default::default()
+    })
+    .unwrap();
+  assert_eq!(map.get_source(0), Some("C:/project/src/a.ts"));
+  assert_eq!(map.get_source_contents(0), Some("const a: number = 1;"));
+  assert_eq!(map.get_source_root(), Some("/"));
+  assert_eq!(map.to_decoded().source_root.as_deref(), Some("/"));
+  assert!(map
+    .to_json(&Default::default())
+    .contains(r#""sourceRoot":"/","sources":["C:/project/src/a.ts"]"#));
+}
+
+#[test]
+fn bundle_untraceable_tokens() {
+  use enhanced_magic_string::types::UntraceableTokenStrategy;
+
+  let dir = "tests/fixtures/collapse-sourcemap/untraceable-tokens";
+  let read = |file: &str| std::fs::read_to_string(format!("{dir}/{file}")).unwrap();
+
+  for (strategy, sources, mappings) in [
+    (UntraceableTokenStrategy::Drop, vec!["a.ts"], "aAAO,MAAMA;"),
+    (
+      UntraceableTokenStrategy::KeepIntermediate,
+      vec!["a.js", "a.ts"],
+      "AAAA,aCAO,MAAMA;",
+    ),
+    (
+      UntraceableTokenStrategy::NearestOnLine,
+      vec!["a.ts"],
+      "AAAO,aAAA,MAAMA;",
+    ),
+    (
+      UntraceableTokenStrategy::Unmapped,
+      vec!["a.ts"],
+      "A,aAAO,MAAMA;",
+    ),
+  ] {
+    let mut m = MagicString::new(
+      &read("a.min.js"),
+      Some(MagicStringOptions {
+        filename: Some("a.min.js".to_string()),
+        source_map_chain: vec![Arc::new(read("a.js.map")), Arc::new(read("a.min.js.map"))],
+        ..Default::default()
+      }),
+    );
+    m.add_sourcemap_location(13);
+    m.add_sourcemap_location(19);
+
+    let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions {
+      trace_source_map_chain: Some(true),
+      ..Default::default()
+    });
+    bundle.add_source(m, None).unwrap();
+
+    let map = bundle
+      .generate_map(SourceMapOptions {
+        untraceable_token_strategy: strategy,
+        ..Default::default()
+      })
+      .unwrap();
+    assert_eq!(map.sources().collect::<Vec<_>>(), sources, "{strategy:?}");
+    assert_eq!(map.encoded_mappings(), mappings, "{strategy:?}");
+
+    let decoded = map.decoded_mappings();
+    let segments = mappings.split([',', ';']).filter(|s| !s.is_empty());
+    assert_eq!(
+      decoded.iter().flatten().count(),
+      segments.count(),
+      "{strategy:?}"
+    );
+    assert_eq!(
+      decoded[0][0].source.is_none(),
+      strategy == UntraceableTokenStrategy::Unmapped,
+      "{strategy:?}"
+    );
+  }
+}
+
+#[test]
+fn bundle_lookup_bias() {
+  use enhanced_magic_string::types::LookupBias;
+
+  // a.js maps only the start of `const a = 1;` to a.ts, the content of a.ts is unknown so columns inside the segment
+  // are not composed
+  let chain_map = r#"{"version":3,"sources":["a.ts"],"names":[],"mappings":"AAAA"}"#;
+
+  for (lookup_bias, mappings) in [
+    (LookupBias::GreatestLowerBound, "AAAA,MAAA"),
+    (LookupBias::Exact, "AAAA"),
+  ] {
+    let mut m = MagicString::new(
+      "const a = 1;",
+      Some(MagicStringOptions {
+        filename: Some("a.js".to_string()),
+        source_map_chain: vec![Arc::new(chain_map.to_string())],
+        ..Default::default()
+      }),
+    );
+    m.add_sourcemap_location(6);
+
+    let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions {
+      trace_source_map_chain: Some(true),
+      ..Default::default()
+    });
+    bundle.add_source(m, None).unwrap();
+
+    let map = bundle
+      .generate_map(SourceMapOptions {
+        lookup_bias,
+        ..Default::default()
+      })
+      .unwrap();
+    assert_eq!(map.encoded_mappings(), mappings, "{lookup_bias:?}");
+  }
+}
+
+#[test]
+fn bundle_trace_without_content() {
+  // a.js is unchanged from a.ts, so the column inside the segment is composed whether the content is emitted or not
+  let chain_map = r#"{"version":3,"sources":["a.ts"],"sourcesContent":["const a = 1;"],"names":[],"mappings":"AAAA"}"#;
+
+  for include_content in [true, false] {
+    let mut m = MagicString::new(
+      "const a = 1;",
+      Some(MagicStringOptions {
+        filename: Some("a.js".to_string()),
+        source_map_chain: vec![Arc::new(chain_map.to_string())],
+        ..Default::default()
+      }),
     );
+    m.add_sourcemap_location(6);
+
+    let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions {
+      trace_source_map_chain: Some(true),
+      ..Default::default()
+    });
+    bundle.add_source(m, None).unwrap();
+
+    let map = bundle
+      .generate_map(SourceMapOptions {
+        include_content: Some(include_content),
+        ..Default::default()
+      })
+      .unwrap();
+    assert_eq!(map.encoded_mappings(), "AAAA,MAAM", "{include_content}");
+    assert_eq!(
+      map.get_source_contents(0),
+      include_content.then_some("const a = 1;"),
+      "{include_content}"
+    );
+  }
+}
+
+#[test]
+fn bundle_index_map_in_chain() {
+  let index_map = r#"{"version":3,"file":"a.js","sections":[
+    {"offset":{"line":0,"column":0},"map":{"version":3,"sources":["a.ts"],"names":[],"mappings":"AAAA"}},
+    {"offset":{"line":1,"column":0},"map":{"version":3,"sources":["b.ts"],"names":[],"mappings":"AAAA"}}
+  ]}"#;
+  let m = MagicString::new(
+    "a();\nb();",
+    Some(MagicStringOptions {
+      filename: Some("a.js".to_string()),
+      source_map_chain: vec![Arc::new(index_map.to_string())],
+      ..Default::default()
+    }),
+  );
+  assert_eq!(m.get_source_map_chain().len(), 1);
+
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions {
+    trace_source_map_chain: Some(true),
+    ..Default::default()
+  });
+  bundle.add_source(m, None).unwrap();
+
+  let map = bundle.generate_map(SourceMapOptions::default()).unwrap();
+  assert_eq!(map.sources().collect::<Vec<_>>(), vec!["a.ts", "b.ts"]);
+  assert_eq!(map.encoded_mappings(), "AAAA;ACAA");
+}
+
+#[test]
+fn bundle_extension_fields() {
+  use enhanced_magic_string::types::ExtensionFieldsPolicy;
+
+  let chain_map = r#"{"version":3,"file":"mid.js","sources":["app.ts","vendor/lib.js"],"names":[],"mappings":"AAAA;ACAA",
+    "x_google_ignoreList":[1],"x_metro_module_paths":["app","lib"],"x_custom":"mid"}"#;
+  let m = MagicString::new(
+    "app();\nlib();",
+    Some(MagicStringOptions {
+      filename: Some("mid.js".to_string()),
+      source_map_chain: vec![Arc::new(chain_map.to_string())],
+      ..Default::default()
+    }),
+  );
+
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions {
+    trace_source_map_chain: Some(true),
+    ..Default::default()
   });
+  bundle.add_source(m, None).unwrap();
+
+  let map = bundle
+    .generate_map(SourceMapOptions {
+      extension_fields_policy: ExtensionFieldsPolicy::Merge(vec![
+        "x_metro_module_paths".to_string()
+      ]),
+      extension_fields: vec![("x_custom".to_string(), serde_json::json!("out"))],
+      ..Default::default()
+    })
+    .unwrap();
+  assert_eq!(
+    map.sources().collect::<Vec<_>>(),
+    vec!["app.ts", "vendor/lib.js"]
+  );
+  assert_eq!(map.ignore_list(), &[1]);
+  assert_eq!(
+    map.to_json(&Default::default()),
+    r#"{"version":3,"sources":["app.ts","vendor/lib.js"],"names":[],"mappings":"AAAA;ACAA","x_google_ignoreList":[1],"x_custom":"out","x_metro_module_paths":["app","lib"]}"#
+  );
+}
+
+#[test]
+fn bundle_magic_string_ignore_list() {
+  let chain_map = r#"{"version":3,"file":"mid.js","sources":["app.ts","vendor/lib.js"],"names":[],"mappings":"AAAA;ACAA"}"#;
+
+  for trace_source_map_chain in [false, true] {
+    let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions {
+      trace_source_map_chain: Some(trace_source_map_chain),
+      ..Default::default()
+    });
+    let vendor = MagicString::new(
+      "v();",
+      Some(MagicStringOptions {
+        filename: Some("node_modules/v.js".to_string()),
+        ignore_list: vec!["node_modules/v.js".into()],
+        ..Default::default()
+      }),
+    );
+    let mid = MagicString::new(
+      "app();\nlib();",
+      Some(MagicStringOptions {
+        filename: Some("mid.js".to_string()),
+        ignore_list: vec!["vendor/lib.js".into()],
+        source_map_chain: vec![Arc::new(chain_map.to_string())],
+        ..Default::default()
+      }),
+    );
+    bundle.add_source(vendor, None).unwrap();
+    bundle
+      .add_source(
+        mid,
+        Some(AddSourceOptions {
+          separator: Some("\n".into()),
+          ..Default::default()
+        }),
+      )
+      .unwrap();
+
+    let map = bundle.generate_map(SourceMapOptions::default()).unwrap();
+    let ignored = map
+      .ignore_list()
+      .iter()
+      .map(|src_id| map.get_source(*src_id).unwrap())
+      .collect::<Vec<_>>();
+
+    if trace_source_map_chain {
+      assert_eq!(ignored, vec!["node_modules/v.js", "vendor/lib.js"]);
+    } else {
+      assert_eq!(ignored, vec!["node_modules/v.js"]);
+    }
+  }
+}
+
+#[test]
+fn bundle_edited_chunk() {
+  let m = MagicString::new(
+    "const a = 1;",
+    Some(MagicStringOptions {
+      filename: Some("a.js".to_string()),
+      ..Default::default()
+    }),
+  );
+  {
+    let mut chunk = m.first_chunk.lock();
+    chunk.content = "let b\n= 2;".into();
+    chunk.edited = true;
+  }
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+  bundle.prepend("/* header */ ");
+  bundle.add_source(m, None).unwrap();
+
+  assert_eq!(bundle.to_string(), "/* header */ let b\n= 2;");
+  let map = bundle.generate_map(SourceMapOptions::default()).unwrap();
+  // like magic-string, every line of the edited content maps to the start of the chunk
+  assert_eq!(
+    map
+      .tokens()
+      .map(|token| (token.get_dst(), token.get_src()))
+      .collect::<Vec<_>>(),
+    vec![((0, 13), (0, 0)), ((1, 0), (0, 0))]
+  );
+}
+
+#[test]
+fn bundle_indent_after_inline_wrapper() {
+  let mut bundle = enhanced_magic_string::bundle::Bundle::new(BundleOptions::default());
+
+  for (filename, content) in [("a.js", "a();"), ("b.js", "b();")] {
+    let m = MagicString::new(
+      content,
+      Some(MagicStringOptions {
+        filename: Some(filename.to_string()),
+        ..Default::default()
+      }),
+    );
+    bundle.add_source(m, None).unwrap();
+  }
+  bundle.wrap("(function () { ", "\n})();");
+  bundle.indent(Some("  "));
+
+  // the first line continues the line of the wrapper, it is not indented
+  assert_eq!(bundle.to_string(), "(function () { a();\n  b();\n})();");
+  let map = bundle.generate_map(SourceMapOptions::default()).unwrap();
+  assert_eq!(map.lookup_token(0, 15).unwrap().get_source(), Some("a.js"));
+  assert_eq!(map.lookup_token(1, 2).unwrap().get_source(), Some("b.js"));
 }
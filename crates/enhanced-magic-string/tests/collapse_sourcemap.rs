@@ -0,0 +1,402 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use enhanced_magic_string::{
+  collapse_sourcemap::{
+    collapse_sourcemap_chain, collapse_sourcemap_chain_with_extensions, lookup_token_with_bias,
+    CollapseSourcemapOptions,
+  },
+  source_map::ChainSourceMap,
+  types::{ExtensionFieldsPolicy, LookupBias, UntraceableTokenStrategy},
+};
+use sourcemap::{SourceMap, SourceMapBuilder};
+
+use crate::common::normalize_newlines;
+
+mod common;
+
+/// A map of `file` whose every line maps to the same line of `source`, without source content.
+fn line_map(file: &str, source: &str, lines: u32) -> SourceMap {
+  let mut builder = SourceMapBuilder::new(Some(file));
+  let src_id = builder.add_source(source);
+
+  for line in 0..lines {
+    builder.add_raw(line, 0, line, 0, Some(src_id), None, false);
+  }
+
+  builder.into_sourcemap()
+}
+
+#[test]
+fn collapse_with_source_resolver() {
+  let chain = vec![
+    line_map("dist/a.js", "a.ts", 2),
+    line_map("dist/a.min.js", "a.js", 2),
+  ];
+  let resolver: HashMap<PathBuf, String> = HashMap::from([(
+    PathBuf::from("dist/a.ts"),
+    "const a: number = 1;\nconsole.log(a);".to_string(),
+  )]);
+
+  let map = collapse_sourcemap_chain(
+    chain,
+    CollapseSourcemapOptions {
+      source_resolver: Some(Arc::new(resolver)),
+      ..Default::default()
+    },
+  );
+
+  assert_eq!(map.get_source(0), Some("a.ts"));
+  assert_eq!(
+    map.get_source_contents(0),
+    Some("const a: number = 1;\nconsole.log(a);")
+  );
+}
+
+#[test]
+fn collapse_with_source_resolver_without_file() {
+  let resolver: HashMap<PathBuf, String> =
+    HashMap::from([(PathBuf::from("a.ts"), "const a: number = 1;".to_string())]);
+
+  // the sources of a map without a directory are resolved as they are
+  for file in [None, Some(""), Some("/")] {
+    let mut builder = SourceMapBuilder::new(file);
+    let src_id = builder.add_source("a.ts");
+    builder.add_raw(0, 0, 0, 0, Some(src_id), None, false);
+    let chain = vec![builder.into_sourcemap(), line_map("a.min.js", "a.js", 1)];
+
+    let map = collapse_sourcemap_chain(
+      chain,
+      CollapseSourcemapOptions {
+        source_resolver: Some(Arc::new(resolver.clone())),
+        ..Default::default()
+      },
+    );
+
+    assert_eq!(map.get_source(0), Some("a.ts"), "{file:?}");
+    assert_eq!(
+      map.get_source_contents(0),
+      Some("const a: number = 1;"),
+      "{file:?}"
+    );
+  }
+}
+
+#[test]
+fn collapse_with_source_root() {
+  let mut original = line_map("dist/a.js", "a.ts", 2);
+  original.set_source_root(Some("../src/"));
+  let chain = vec![original, line_map("dist/a.min.js", "a.js", 2)];
+  let resolver: HashMap<PathBuf, String> = HashMap::from([(
+    PathBuf::from("src/a.ts"),
+    "const a: number = 1;\nconsole.log(a);".to_string(),
+  )]);
+
+  let map = collapse_sourcemap_chain(
+    chain,
+    CollapseSourcemapOptions {
+      source_resolver: Some(Arc::new(resolver)),
+      ..Default::default()
+    },
+  );
+
+  assert_eq!(map.get_source(0), Some("../src/a.ts"));
+  assert_eq!(
+    map.get_source_contents(0),
+    Some("const a: number = 1;\nconsole.log(a);")
+  );
+}
+
+#[test]
+fn collapse_untraceable_tokens() {
+  fixture!(
+    "tests/fixtures/collapse-sourcemap/untraceable-tokens/output/*.js.map",
+    |file, _| {
+      println!("[collapse untraceable tokens test] file: {:?}", file);
+      let dir = file.parent().unwrap().parent().unwrap();
+      let chain = ["a.js.map", "a.min.js.map"]
+        .iter()
+        .map(|map| SourceMap::from_slice(&std::fs::read(dir.join(map)).unwrap()).unwrap())
+        .collect::<Vec<_>>();
+      let strategy = match file.file_name().unwrap().to_str().unwrap() {
+        "drop.js.map" => UntraceableTokenStrategy::Drop,
+        "keep-intermediate.js.map" => UntraceableTokenStrategy::KeepIntermediate,
+        "nearest-on-line.js.map" => UntraceableTokenStrategy::NearestOnLine,
+        "unmapped.js.map" => UntraceableTokenStrategy::Unmapped,
+        name => panic!("unknown strategy of {name}"),
+      };
+
+      let map = collapse_sourcemap_chain(
+        chain,
+        CollapseSourcemapOptions {
+          untraceable_token_strategy: strategy,
+          ..Default::default()
+        },
+      );
+      let mut buf = vec![];
+      map.to_writer(&mut buf).unwrap();
+      let map_str = String::from_utf8(buf).unwrap();
+
+      let expected = std::fs::read_to_string(&file).unwrap();
+      assert_eq!(
+        normalize_newlines(&map_str),
+        normalize_newlines(expected.trim_end())
+      );
+    }
+  );
+}
+
+#[test]
+fn collapse_column_offset_in_segment() {
+  // a lo-res map of `file` whose line 0 maps to line 0 of `source` by a single segment
+  let lo_res_map = |file: &str, source: &str, content: Option<&str>, name: Option<&str>| {
+    let mut builder = SourceMapBuilder::new(Some(file));
+    let src_id = builder.add_source(source);
+    builder.set_source_contents(src_id, content);
+    let name_id = name.map(|name| builder.add_name(name));
+    builder.add_raw(0, 0, 0, 0, Some(src_id), name_id, false);
+    builder.into_sourcemap()
+  };
+  // a hi-res map of `a.min.js` that maps `answer` of `/* banner */const answer = 42;` to column 6 of `a.js`
+  let hi_res_map = |content: Option<&str>| {
+    let mut builder = SourceMapBuilder::new(Some("a.min.js"));
+    let src_id = builder.add_source("a.js");
+    builder.set_source_contents(src_id, content);
+    builder.add_raw(0, 12, 0, 0, Some(src_id), None, false);
+    builder.add_raw(0, 18, 0, 6, Some(src_id), None, false);
+    builder.into_sourcemap()
+  };
+  let original_col = |chain: Vec<SourceMap>| {
+    let map = collapse_sourcemap_chain(chain, CollapseSourcemapOptions::default());
+    map.lookup_token(0, 18).unwrap().get_src_col()
+  };
+
+  // the text of the lo-res segment is unchanged, so the column inside it is kept
+  assert_eq!(
+    original_col(vec![
+      lo_res_map("a.js", "a.ts", Some("const answer = 42;"), None),
+      hi_res_map(Some("const answer = 42;")),
+    ]),
+    6
+  );
+  // the text of the segment is changed, so it maps to the start of the segment
+  assert_eq!(
+    original_col(vec![
+      lo_res_map("a.js", "a.ts", Some("let answer = 42;"), None),
+      hi_res_map(Some("const answer = 42;")),
+    ]),
+    0
+  );
+  // a named segment may be renamed
+  assert_eq!(
+    original_col(vec![
+      lo_res_map("a.js", "a.ts", Some("const answer = 42;"), Some("answer")),
+      hi_res_map(Some("const answer = 42;")),
+    ]),
+    0
+  ); // the text can not be compared without the original or the generated content
+  assert_eq!(
+    original_col(vec![
+      lo_res_map("a.js", "a.ts", None, None),
+      hi_res_map(Some("const answer = 42;")),
+    ]),
+    0
+  );
+  assert_eq!(
+    original_col(vec![
+      lo_res_map("a.js", "a.ts", Some("const answer = 42;"), None),
+      hi_res_map(None),
+    ]),
+    0
+  );
+}
+
+#[test]
+fn lookup_bias() {
+  let mut builder = SourceMapBuilder::new(Some("a.js"));
+  let src_id = builder.add_source("a.ts");
+
+  for (line, col) in [(0, 0), (0, 10), (1, 4)] {
+    builder.add_raw(line, col, line, col, Some(src_id), None, false);
+  }
+
+  let map = builder.into_sourcemap();
+  let lookup =
+    |line, col, bias| lookup_token_with_bias(&map, line, col, bias).map(|token| token.get_dst());
+
+  assert_eq!(lookup(0, 5, LookupBias::GreatestLowerBound), Some((0, 0)));
+  assert_eq!(lookup(1, 2, LookupBias::GreatestLowerBound), Some((1, 4)));
+  assert_eq!(lookup(0, 5, LookupBias::LeastUpperBound), Some((0, 10)));
+  assert_eq!(lookup(0, 10, LookupBias::LeastUpperBound), Some((0, 10)));
+  assert_eq!(lookup(0, 11, LookupBias::LeastUpperBound), None);
+  assert_eq!(lookup(1, 2, LookupBias::LeastUpperBound), Some((1, 4)));
+  assert_eq!(lookup(0, 5, LookupBias::Exact), None);
+  assert_eq!(lookup(0, 10, LookupBias::Exact), Some((0, 10)));
+}
+
+#[test]
+fn collapse_with_exact_lookup() {
+  // a.min.js maps column 0 and 3 of each line to a.js, which only maps column 0
+  let mut builder = SourceMapBuilder::new(Some("a.min.js"));
+  let src_id = builder.add_source("a.js");
+
+  for line in 0..2 {
+    builder.add_raw(line, 0, line, 0, Some(src_id), None, false);
+    builder.add_raw(line, 3, line, 3, Some(src_id), None, false);
+  }
+
+  let min_map = builder.into_sourcemap();
+  let collapse = |lookup_bias| {
+    let chain = vec![line_map("a.js", "a.ts", 2), min_map.clone()];
+    let map = collapse_sourcemap_chain(
+      chain,
+      CollapseSourcemapOptions {
+        lookup_bias,
+        ..Default::default()
+      },
+    );
+    map
+      .tokens()
+      .map(|token| token.get_dst())
+      .collect::<Vec<_>>()
+  };
+
+  assert_eq!(
+    collapse(LookupBias::GreatestLowerBound),
+    vec![(0, 0), (0, 3), (1, 0), (1, 3)]
+  );
+  assert_eq!(collapse(LookupBias::Exact), vec![(0, 0), (1, 0)]);
+}
+
+#[test]
+fn collapse_multi_source_intermediate_map() {
+  // out.js concatenates b.js, compiled from b.ts, and x.js, which has no map
+  let mut builder = SourceMapBuilder::new(Some("out.js"));
+  let b_id = builder.add_source("b.js");
+  let x_id = builder.add_source("x.js");
+  builder.add_raw(0, 0, 0, 0, Some(b_id), None, false);
+  builder.add_raw(1, 0, 0, 0, Some(x_id), None, false);
+  let concat_map = builder.into_sourcemap();
+
+  let map = collapse_sourcemap_chain(
+    vec![line_map("b.js", "b.ts", 1), concat_map],
+    CollapseSourcemapOptions {
+      inline_content: false,
+      ..Default::default()
+    },
+  );
+
+  assert_eq!(
+    map
+      .tokens()
+      .map(|token| (token.get_dst_line(), token.get_source().unwrap()))
+      .collect::<Vec<_>>(),
+    vec![(0, "b.ts"), (1, "x.js")]
+  );
+}
+
+#[test]
+fn collapse_with_extension_fields() {
+  // mid.js concatenates app.ts and the ignored vendor/lib.js, out.js swaps their lines
+  let mid_map = r#"{"version":3,"file":"mid.js","sources":["app.ts","vendor/lib.js"],"names":[],"mappings":"AAAA;ACAA",
+    "x_google_ignoreList":[1],"x_source_hashes":["h-app","h-lib"],"x_range":[0,1],"x_custom":"mid"}"#;
+  let out_map =
+    r#"{"version":3,"file":"out.js","sources":["mid.js"],"names":[],"mappings":"AACA;AADA"}"#;
+
+  let collapse = |mid_map: &str, extension_fields_policy| {
+    let chain = [mid_map, out_map]
+      .iter()
+      .map(|map| ChainSourceMap::from_slice(map.as_bytes()).unwrap())
+      .collect::<Vec<_>>();
+
+    collapse_sourcemap_chain_with_extensions(
+      chain,
+      CollapseSourcemapOptions {
+        inline_content: false,
+        extension_fields_policy,
+        ..Default::default()
+      },
+    )
+  };
+
+  let map = collapse(mid_map, ExtensionFieldsPolicy::Drop);
+  assert_eq!(
+    map.sources().collect::<Vec<_>>(),
+    vec!["vendor/lib.js", "app.ts"]
+  );
+  assert_eq!(map.ignore_list(), &[0]);
+  assert!(map.extension_fields().is_empty());
+
+  // the standard `ignoreList` is carried forward too
+  let map = collapse(
+    &mid_map.replace("x_google_ignoreList", "ignoreList"),
+    ExtensionFieldsPolicy::Drop,
+  );
+  assert_eq!(map.ignore_list(), &[0]);
+
+  // `x_source_hashes` of mid.js is in the order of its sources, which are swapped, but it is only remapped if it is
+  // named as a per-source field
+  let map = collapse(mid_map, ExtensionFieldsPolicy::Passthrough);
+  assert_eq!(
+    map.extension_fields(),
+    &[
+      ("x_custom".to_string(), serde_json::json!("mid")),
+      ("x_range".to_string(), serde_json::json!([0, 1])),
+      (
+        "x_source_hashes".to_string(),
+        serde_json::json!(["h-app", "h-lib"])
+      ),
+    ]
+  );
+
+  // `x_range` has as many entries as there are sources, but it is not a per-source field
+  let map = collapse(
+    mid_map,
+    ExtensionFieldsPolicy::Merge(vec!["x_source_hashes".to_string()]),
+  );
+  assert_eq!(
+    map.extension_fields(),
+    &[
+      ("x_custom".to_string(), serde_json::json!("mid")),
+      ("x_range".to_string(), serde_json::json!([0, 1])),
+      (
+        "x_source_hashes".to_string(),
+        serde_json::json!(["h-lib", "h-app"])
+      ),
+    ]
+  );
+  assert!(map.to_json(&Default::default()).ends_with(
+    r#""x_google_ignoreList":[0],"x_custom":"mid","x_range":[0,1],"x_source_hashes":["h-lib","h-app"]}"#
+  ));
+}
+
+#[test]
+fn collapse_large_chain() {
+  // enough tokens to be traced on multiple threads
+  let lines = 20_000;
+  let mut builder = SourceMapBuilder::new(Some("dist/a.min.js"));
+  let src_id = builder.add_source("a.js");
+
+  for line in 0..lines {
+    builder.add_raw(line, 0, lines - line - 1, 0, Some(src_id), None, false);
+  }
+
+  let min_map = builder.into_sourcemap();
+
+  // on the available threads, then on the calling thread only
+  for trace_threads in [None, Some(1)] {
+    let map = collapse_sourcemap_chain(
+      vec![line_map("a.js", "a.ts", lines), min_map.clone()],
+      CollapseSourcemapOptions {
+        inline_content: false,
+        trace_threads,
+        ..Default::default()
+      },
+    );
+
+    assert_eq!(map.get_token_count(), lines);
+    assert!(map.tokens().all(
+      |token| token.get_src() == (lines - token.get_dst_line() - 1, 0)
+        && token.get_source() == Some("a.ts")
+    ));
+  }
+}
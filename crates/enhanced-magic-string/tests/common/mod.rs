@@ -20,11 +20,11 @@ where
 macro_rules! fixture {
   ($pattern:expr, $op:expr) => {
     if cfg!(debug_assertions) {
-      crate::common::fixture_debug($pattern, file!(), $op);
+      $crate::common::fixture_debug($pattern, file!(), $op);
       return;
     }
 
-    crate::common::fixture($pattern, $op);
+    $crate::common::fixture($pattern, $op);
   };
 }
 
@@ -65,6 +65,7 @@ where
 }
 
 // Solve some line break and path mismatch issues that occur across platforms.
+#[allow(dead_code)]
 pub fn normalize_newlines(input: &str) -> String {
   input.replace("\r\n", "\n").replace("\\\\", "/")
 }
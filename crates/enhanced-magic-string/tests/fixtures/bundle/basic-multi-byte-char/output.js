@@ -17,4 +17,5 @@ function 主要入口() {
   console.log('在模块2中');
   console.log('也在模块2中');
   return "模块2";
-}/* end of module *///# sourceMappingURL=output.js.map
\ No newline at end of file
+}/* end of module */
+//# sourceMappingURL=output.js.map
\ No newline at end of file
@@ -17,4 +17,5 @@ main();
   console.log('in m2');
   console.log('in m2 too');
   return "m2";
-}/* end of module *///# sourceMappingURL=output.js.map
\ No newline at end of file
+}/* end of module */
+//# sourceMappingURL=output.js.map
\ No newline at end of file
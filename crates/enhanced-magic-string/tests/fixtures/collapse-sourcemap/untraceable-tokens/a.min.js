@@ -0,0 +1 @@
+"use strict";const a=1;
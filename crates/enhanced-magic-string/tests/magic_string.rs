@@ -2,7 +2,8 @@ use std::fs;
 
 use enhanced_magic_string::{
   magic_string::{MagicString, MagicStringOptions},
-  types::SourceMapOptions,
+  source_map::SourceMapJsonOptions,
+  types::{MappingsOptionHires, Segment, SegmentSource, SourceMapOptions},
 };
 
 use crate::common::normalize_newlines;
@@ -38,6 +39,10 @@ fn magic_string() {
 
     let code = s.to_string();
 
+    let mut code_buf = vec![];
+    s.write_to(&mut code_buf).unwrap();
+    assert_eq!(String::from_utf8(code_buf).unwrap(), code);
+
     let expect_code = fs::read_to_string(dir.join("basic.output.js")).unwrap();
 
     let mut str_buf = vec![];
@@ -45,10 +50,199 @@ fn magic_string() {
     let map_str = String::from_utf8(str_buf).unwrap();
     let expect_map = fs::read_to_string(dir.join("basic.js.map")).unwrap();
 
+    // the mappings encoded while generating keep the trailing empty lines like magic-string
+    let expect_mappings = expect_map
+      .split("\"mappings\":\"")
+      .nth(1)
+      .and_then(|rest| rest.split('"').next())
+      .unwrap();
+    assert_eq!(map.encoded_mappings(), expect_mappings);
+
     assert_eq!(normalize_newlines(&code), normalize_newlines(&expect_code));
     assert_eq!(
       normalize_newlines(&map_str),
-      normalize_newlines(&expect_map.replace(";\"}", "\"}"))
+      normalize_newlines(&expect_map)
     );
   });
 }
+
+#[test]
+fn magic_string_selective_hires() {
+  let generated_positions = |s: &MagicString, hires: MappingsOptionHires| {
+    s.generate_map(SourceMapOptions {
+      hires: Some(hires),
+      ..Default::default()
+    })
+    .unwrap()
+    .tokens()
+    .map(|token| (token.get_dst_line(), token.get_dst_col()))
+    .collect::<Vec<_>>()
+  };
+
+  let mut s = MagicString::new("const a = 1;\nconst b = 2;", None);
+  assert_eq!(
+    generated_positions(&s, MappingsOptionHires::Bool(false)),
+    vec![(0, 0), (1, 0)]
+  );
+
+  s.add_sourcemap_location(6);
+  assert_eq!(
+    generated_positions(&s, MappingsOptionHires::Bool(false)),
+    vec![(0, 0), (0, 6), (1, 0)]
+  );
+
+  // overlapping and unsorted ranges are merged
+  assert_eq!(
+    generated_positions(&s, MappingsOptionHires::Ranges(vec![(19, 21), (17, 20)])),
+    vec![(0, 0), (0, 6), (1, 0), (1, 4), (1, 5), (1, 6), (1, 7)]
+  );
+
+  // hi-res within 2 chars of the prepended and appended content
+  let mut s = MagicString::new("const a = 1;\nconst b = 2;", None);
+  s.prepend("/* a */");
+  s.append("\n");
+  assert_eq!(
+    generated_positions(&s, MappingsOptionHires::AroundEdits(2)),
+    vec![(0, 7), (0, 8), (1, 0), (1, 10), (1, 11)]
+  );
+  // a radius past the end of the string makes every char hi-res
+  assert_eq!(
+    generated_positions(&s, MappingsOptionHires::AroundEdits(usize::MAX)).len(),
+    25
+  );
+
+  let s = MagicString::new("föo.bar_1(ä)", None);
+  assert_eq!(
+    generated_positions(&s, MappingsOptionHires::Boundary),
+    vec![(0, 0), (0, 3), (0, 4), (0, 9), (0, 10), (0, 11)]
+  );
+
+  // identifiers with combining marks, connector punctuation and non-latin digits are single words like `\w+`
+  let s = MagicString::new("cafe\u{301}.a\u{203f}b(x\u{661})", None);
+  assert_eq!(
+    generated_positions(&s, MappingsOptionHires::Boundary),
+    vec![(0, 0), (0, 5), (0, 6), (0, 9), (0, 10), (0, 12)]
+  );
+}
+
+#[test]
+fn magic_string_decoded_map_and_json_options() {
+  let mut s = MagicString::new(
+    "a\nb",
+    Some(MagicStringOptions {
+      filename: Some("a.js".to_string()),
+      ..Default::default()
+    }),
+  );
+  s.append("\n");
+
+  // the outro is not part of the mappings, like magic-string
+  let opts = || SourceMapOptions {
+    source: Some("a.js".to_string()),
+    ..Default::default()
+  };
+  let segment = |original_line| Segment {
+    generated_column: 0,
+    source: Some(SegmentSource {
+      source_index: 0,
+      original_line,
+      original_column: 0,
+      name_index: None,
+    }),
+  };
+
+  let decoded = s.generate_decoded_map(opts()).unwrap();
+  assert_eq!(decoded.sources, vec!["a.js".to_string()]);
+  assert_eq!(decoded.mappings, vec![vec![segment(0)], vec![segment(1)]]);
+
+  let map = s.generate_map(opts()).unwrap();
+  assert_eq!(
+    map.to_json(&SourceMapJsonOptions::default()),
+    r#"{"version":3,"sources":["a.js"],"names":[],"mappings":"AAAA;AACA"}"#
+  );
+  assert_eq!(
+    map.to_json(&SourceMapJsonOptions {
+      pretty: true,
+      sort_keys: true,
+      omit_empty_names: true,
+    }),
+    "{\n  \"mappings\": \"AAAA;AACA\",\n  \"sources\": [\n    \"a.js\"\n  ],\n  \"version\": 3\n}"
+  );
+}
+
+#[test]
+fn magic_string_extract_source_map() {
+  let inner = MagicString::new(
+    "const a = 1;",
+    Some(MagicStringOptions {
+      filename: Some("a.ts".to_string()),
+      ..Default::default()
+    }),
+  );
+  let url = inner
+    .generate_map(SourceMapOptions::default())
+    .unwrap()
+    .to_url();
+  let code = format!("const a = 1;\n//# sourceMappingURL={url}\n");
+
+  let s = MagicString::new(&code, None);
+  assert_eq!(s.to_string(), code);
+  assert!(s.get_source_map_chain().is_empty());
+
+  let s = MagicString::new(
+    &code,
+    Some(MagicStringOptions {
+      extract_source_map: true,
+      ..Default::default()
+    }),
+  );
+  assert_eq!(s.to_string(), "const a = 1;\n");
+  assert_eq!(s.get_source_map_chain().len(), 1);
+
+  // external maps are resolved relative to the filename
+  let s = MagicString::new(
+    "a();\n/*# sourceMappingURL=input.js.map */",
+    Some(MagicStringOptions {
+      filename: Some("tests/fixtures/combine-string/basic/input.js".to_string()),
+      extract_source_map: true,
+      ..Default::default()
+    }),
+  );
+  assert_eq!(s.to_string(), "a();\n");
+  assert_eq!(s.get_source_map_chain().len(), 1);
+
+  // the stale comment is stripped even if the map is missing
+  let s = MagicString::new(
+    "a();\n//# sourceMappingURL=missing.js.map",
+    Some(MagicStringOptions {
+      extract_source_map: true,
+      ..Default::default()
+    }),
+  );
+  assert_eq!(s.to_string(), "a();\n");
+  assert!(s.get_source_map_chain().is_empty());
+
+  // percent-encoded data urls are decoded
+  let s = MagicString::new(
+    "a();\n//# sourceMappingURL=data:application/json,%7B%22version%22%3A3%2C%22sources%22%3A%5B%22a.ts%22%5D%2C%22names%22%3A%5B%5D%2C%22mappings%22%3A%22AAAA%22%7D",
+    Some(MagicStringOptions {
+      extract_source_map: true,
+      ..Default::default()
+    }),
+  );
+  assert_eq!(s.to_string(), "a();\n");
+  let chain = s.get_source_map_chain();
+  assert_eq!(chain.len(), 1);
+  assert_eq!(chain[0].get_source(0), Some("a.ts"));
+
+  // a malformed map is dropped instead of failing when the chain is used
+  let s = MagicString::new(
+    "a();\n//# sourceMappingURL=data:application/json,{\"version\":3,",
+    Some(MagicStringOptions {
+      extract_source_map: true,
+      ..Default::default()
+    }),
+  );
+  assert_eq!(s.to_string(), "a();\n");
+  assert!(s.get_source_map_chain().is_empty());
+}
@@ -9,7 +9,7 @@ fn get_relative_path_case() {
   fixture!("tests/fixtures/get-relative-path/output.txt", |file, _| {
     let expect_result = fs::read_to_string(file).unwrap();
 
-    let from_to_paths = vec![
+    let from_to_paths = [
       (
         "fixtures/bundle/01/input.js",
         "fixtures/bundle/01/modules/a.js",